@@ -0,0 +1,249 @@
+use std::{sync::Mutex, time::Duration};
+
+use metrics::gauge;
+use tokio::time::Instant;
+use tracing::{debug, warn};
+
+/// State of a [Breaker]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum State {
+    /// Requests go through
+    Closed,
+    /// Requests fail fast until the cool-down is over
+    Open,
+    /// Requests probe whether workers are back, one at a time
+    HalfOpen,
+}
+
+impl State {
+    const ALL: [State; 3] = [State::Closed, State::Open, State::HalfOpen];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            State::Closed => "closed",
+            State::Open => "open",
+            State::HalfOpen => "half_open",
+        }
+    }
+}
+
+/// Circuit breaker around requests to workers
+///
+/// The circuit opens once requests fail a number of times in a row. After the cool-down,
+/// requests probe the workers one at a time: a failed probe opens the circuit again, a
+/// number of successful ones in a row close it.
+pub struct Breaker {
+    failure_threshold: u32,
+    success_threshold: u32,
+    cool_down: Duration,
+    /// ID of the processor the state is reported for
+    processor_id: String,
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    state: State,
+    /// Failures in a row while closed, successful probes in a row while half-open
+    count: u32,
+    opened_at: Instant,
+    /// A probe is in flight while half-open
+    probing: bool,
+}
+
+impl Breaker {
+    pub fn new(
+        failure_threshold: u32,
+        success_threshold: u32,
+        cool_down: Duration,
+        processor_id: String,
+    ) -> Self {
+        let breaker = Self {
+            failure_threshold,
+            success_threshold,
+            cool_down,
+            processor_id,
+            inner: Mutex::new(Inner {
+                state: State::Closed,
+                count: 0,
+                opened_at: Instant::now(),
+                probing: false,
+            }),
+        };
+        breaker.record(State::Closed);
+
+        breaker
+    }
+
+    pub fn state(&self) -> State {
+        self.inner.lock().unwrap().state
+    }
+
+    /// Whether a request may be sent now, it must be followed by [Breaker::success],
+    /// [Breaker::failure] or [Breaker::release] then
+    pub fn allow(&self) -> bool {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            State::Closed => true,
+            State::Open if inner.opened_at.elapsed() >= self.cool_down => {
+                self.transition(&mut inner, State::HalfOpen);
+                inner.probing = true;
+                true
+            }
+            State::Open => false,
+            State::HalfOpen if inner.probing => false,
+            State::HalfOpen => {
+                inner.probing = true;
+                true
+            }
+        }
+    }
+
+    pub fn success(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            State::Closed => inner.count = 0,
+            State::HalfOpen => {
+                inner.probing = false;
+                inner.count += 1;
+                if inner.count >= self.success_threshold {
+                    self.transition(&mut inner, State::Closed);
+                }
+            }
+            // A request sent before the circuit opened
+            State::Open => {}
+        }
+    }
+
+    pub fn failure(&self) {
+        let mut inner = self.inner.lock().unwrap();
+
+        match inner.state {
+            State::Closed => {
+                inner.count += 1;
+                if inner.count >= self.failure_threshold {
+                    self.transition(&mut inner, State::Open);
+                }
+            }
+            State::HalfOpen => self.transition(&mut inner, State::Open),
+            State::Open => {}
+        }
+    }
+
+    /// End a request which tells nothing about the workers, so that another one may probe
+    /// them while half-open
+    pub fn release(&self) {
+        self.inner.lock().unwrap().probing = false;
+    }
+
+    /// Readiness check, which fails unless the circuit is closed
+    pub fn check(&self) -> Result<(), String> {
+        match self.state() {
+            State::Closed => Ok(()),
+            state => Err(format!(
+                "Workers are unavailable, the circuit breaker is {}",
+                state.as_str()
+            )),
+        }
+    }
+
+    fn transition(&self, inner: &mut Inner, state: State) {
+        match state {
+            State::Open => warn!("Open the circuit breaker"),
+            _ => debug!("Circuit breaker is {}", state.as_str()),
+        }
+
+        inner.state = state;
+        inner.count = 0;
+        inner.probing = false;
+        if state == State::Open {
+            inner.opened_at = Instant::now();
+        }
+
+        self.record(state);
+    }
+
+    fn record(&self, state: State) {
+        for other in State::ALL {
+            gauge!(
+                "processor_breaker_state",
+                "processor_id" => self.processor_id.clone(),
+                "state" => other.as_str(),
+            )
+            .set(if other == state { 1.0 } else { 0.0 });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COOL_DOWN: Duration = Duration::from_secs(10);
+
+    fn breaker() -> Breaker {
+        Breaker::new(3, 2, COOL_DOWN, String::from("processor-1"))
+    }
+
+    fn open(breaker: &Breaker) {
+        for _ in 0..3 {
+            assert!(breaker.allow());
+            breaker.failure();
+        }
+        assert_eq!(breaker.state(), State::Open);
+    }
+
+    #[test]
+    fn opens_after_failures_in_a_row() {
+        let breaker = breaker();
+
+        breaker.failure();
+        breaker.failure();
+        breaker.success();
+        breaker.failure();
+        breaker.failure();
+        assert_eq!(breaker.state(), State::Closed);
+        assert!(breaker.check().is_ok());
+
+        breaker.failure();
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.allow());
+        assert!(breaker.check().is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn probes_one_at_a_time_after_cool_down() {
+        let breaker = breaker();
+        open(&breaker);
+
+        tokio::time::advance(COOL_DOWN - Duration::from_millis(1)).await;
+        assert!(!breaker.allow());
+
+        tokio::time::advance(Duration::from_millis(1)).await;
+        assert!(breaker.allow());
+        assert_eq!(breaker.state(), State::HalfOpen);
+        assert!(!breaker.allow());
+
+        breaker.success();
+        assert_eq!(breaker.state(), State::HalfOpen);
+        assert!(breaker.allow());
+        breaker.success();
+        assert_eq!(breaker.state(), State::Closed);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failed_probe_opens_again() {
+        let breaker = breaker();
+        open(&breaker);
+
+        tokio::time::advance(COOL_DOWN).await;
+        assert!(breaker.allow());
+        breaker.failure();
+        assert_eq!(breaker.state(), State::Open);
+        assert!(!breaker.allow());
+
+        tokio::time::advance(COOL_DOWN).await;
+        assert!(breaker.allow());
+    }
+}
@@ -1,4 +1,6 @@
-use clap::Parser;
+use std::net::Ipv4Addr;
+
+use clap::{ArgAction, Parser};
 
 #[derive(Parser)]
 pub struct Cli {
@@ -16,6 +18,93 @@ pub struct Cli {
     )]
     pub nats_port: u16,
 
+    /// Queue group of `nats.request-reply`, replicas of the same group share the messages
+    #[clap(long, env = "QUEUE_GROUP", default_value_t = String::from("PROCESSORS"))]
+    pub queue_group: String,
+
+    /// ID of the processor reported in replies and metrics
+    #[clap(
+        long,
+        env = "INSTANCE_ID",
+        default_value_t = uuid::Uuid::new_v4().to_string()
+    )]
+    pub instance_id: String,
+
+    #[clap(
+        long,
+        env = "HEALTH_IP",
+        default_value_t = Ipv4Addr::UNSPECIFIED.to_string()
+    )]
+    pub health_ip: String,
+
+    #[clap(
+        long,
+        env = "HEALTH_PORT",
+        default_value_t = 3001
+    )]
+    pub health_port: u16,
+
+    /// Max number of messages passed on to workers at a time
+    #[clap(
+        long,
+        env = "CONCURRENCY",
+        default_value_t = 64,
+        value_parser = clap::value_parser!(u16).range(1..),
+    )]
+    pub concurrency: u16,
+
+    /// Times a request no worker has answered is retried
+    #[clap(long, env = "RETRIES", default_value_t = 3)]
+    pub retries: u32,
+
+    /// Milliseconds before the first retry, doubled for every next one and jittered
+    #[clap(long, env = "RETRY_BACKOFF", default_value_t = 50)]
+    pub retry_backoff: u64,
+
+    /// Max milliseconds between retries
+    #[clap(long, env = "MAX_RETRY_BACKOFF", default_value_t = 1000)]
+    pub max_retry_backoff: u64,
+
+    /// Max milliseconds a worker has to answer each attempt, cut to what is left of the
+    /// timeout of a message
+    #[clap(
+        long,
+        env = "ATTEMPT_TIMEOUT",
+        default_value_t = 10000,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub attempt_timeout: u64,
+
+    /// Failed requests in a row opening the circuit breaker around workers
+    #[clap(
+        long,
+        env = "BREAKER_FAILURES",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub breaker_failures: u32,
+
+    /// Successful probes in a row closing the circuit breaker again
+    #[clap(
+        long,
+        env = "BREAKER_SUCCESSES",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub breaker_successes: u32,
+
+    /// Seconds the circuit breaker stays open before requests probe workers again
+    #[clap(long, env = "BREAKER_COOL_DOWN", default_value_t = 10)]
+    pub breaker_cool_down: u64,
+
+    /// Apply the last known command locally when no worker answers
+    #[clap(long, env = "FALLBACK", default_value_t = false, action = ArgAction::Set)]
+    pub fallback: bool,
+
+    /// Seconds given to in-flight work to finish once shutdown is requested
+    #[clap(long, env = "SHUTDOWN_GRACE_PERIOD", default_value_t = 30)]
+    pub shutdown_grace_period: u64,
+
     #[clap(flatten)]
     pub instrumentation: instrumentation::Instrumentation,
 }
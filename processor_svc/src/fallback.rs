@@ -0,0 +1,129 @@
+use std::{str::from_utf8, sync::RwLock};
+
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, DeliverPolicy},
+    },
+    Client, Message,
+};
+use color_eyre::Result;
+use command::{header, Command};
+use futures::StreamExt;
+use tracing::{debug, info, trace};
+
+/// Value of [header::PROCESSED_BY] in replies the processor has made itself
+pub const PROCESSED_BY: &str = "fallback";
+
+/// Degraded mode, in which the processor applies the last known command itself while no
+/// worker answers
+pub struct Fallback {
+    command: RwLock<Command>,
+}
+
+impl Fallback {
+    pub fn new(command: Command) -> Self {
+        Self {
+            command: RwLock::new(command),
+        }
+    }
+
+    pub fn command(&self) -> Command {
+        *self.command.read().unwrap()
+    }
+
+    pub fn update(&self, command: Command) {
+        *self.command.write().unwrap() = command;
+    }
+
+    /// Apply the command the message overrides the last known one with, if any, the way a
+    /// worker would
+    pub fn apply(&self, api_msg: &Message) -> Result<(Command, String)> {
+        let command = match api_msg
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(header::COMMAND))
+        {
+            Some(code) => code.to_string().try_into()?,
+            None => self.command(),
+        };
+        debug!("Apply {:?} locally", command);
+
+        Ok((
+            command,
+            command.call_on(from_utf8(&api_msg.payload)?.to_string()),
+        ))
+    }
+
+    /// Keep the command up to date with the `COMMANDS` stream, starting from the last one
+    /// stored
+    pub async fn track(&self, client: Client) -> Result<()> {
+        info!("Start tracking commands");
+
+        let jetstream = jetstream::new(client);
+        let stream = jetstream
+            .get_or_create_stream(streams::commands::config())
+            .await?;
+        trace!("Stream created");
+
+        let mut messages = stream
+            .create_consumer(pull::OrderedConfig {
+                deliver_policy: DeliverPolicy::Last,
+                ..Default::default()
+            })
+            .await?
+            .messages()
+            .await?;
+        trace!("Consumer created");
+
+        while let Some(msg) = messages.next().await {
+            let msg = msg?;
+            let command = serde_json::from_slice::<Command>(&msg.payload)?;
+            info!("Fallback command is {:?}", command);
+            self.update(command);
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_nats::HeaderMap;
+
+    fn message(payload: &'static [u8], command: Option<&str>) -> Message {
+        Message {
+            subject: "nats.request-reply".into(),
+            reply: None,
+            payload: payload.into(),
+            headers: command.map(|command| {
+                let mut headers = HeaderMap::new();
+                headers.insert(header::COMMAND, command);
+                headers
+            }),
+            status: None,
+            description: None,
+            length: 0,
+        }
+    }
+
+    #[test]
+    fn applies_the_last_known_command_unless_overridden() {
+        let fallback = Fallback::new(Command::default());
+        fallback.update(Command::Reverse);
+
+        let (command, output) = fallback.apply(&message(b"abc", None)).unwrap();
+        assert_eq!(command, Command::Reverse);
+        assert_eq!(output, "cba");
+
+        let (command, output) = fallback
+            .apply(&message(b"abc", Some(Command::ToUpperCase.code())))
+            .unwrap();
+        assert_eq!(command, Command::ToUpperCase);
+        assert_eq!(output, "ABC");
+
+        assert!(fallback.apply(&message(b"abc", Some("nope"))).is_err());
+        assert!(fallback.apply(&message(b"\xff", None)).is_err());
+    }
+}
@@ -1,17 +1,27 @@
+mod breaker;
 mod cli;
+mod fallback;
+mod processor;
+mod retry;
+mod upstream;
 
-use std::str::from_utf8;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
 
-use async_nats::ServerAddr;
+use async_nats::{jetstream, ServerAddr};
+use breaker::Breaker;
 use clap::Parser;
 use cli::Cli;
-use color_eyre::{eyre, Result};
-use futures::StreamExt;
-use tracing::{debug, info, trace};
+use color_eyre::Result;
+use command::Command;
+use fallback::Fallback;
+use futures::future::ready;
+use health::{check, install_metrics, Health};
+use processor::{Processor, NATS_REQUEST_REPLY};
+use retry::Retry;
+use shutdown::Shutdown;
+use tracing::{debug, error, info};
 use url::Url;
 
-const NATS_WORKING_QUEUE: &str = "nats.wq";
-
 #[tokio::main]
 async fn main() -> Result<()> {
     let cli = Cli::parse();
@@ -27,42 +37,77 @@ async fn main() -> Result<()> {
 
     let client = async_nats::connect(addr).await?;
 
-    while let Some(api_msg) = client.subscribe("nats.request-reply").await?.next().await {
-        info!("Got a message from {}", api_msg.subject);
-        debug!("Message: {:?}", api_msg);
-
-        trace!("Send a reqeust to {}", NATS_WORKING_QUEUE);
-        debug!(
-            "{}",
-            from_utf8(&api_msg.payload).map_or_else(
-                |e| format!("Can't create string slice from request payload: {}", e),
-                |s| format!("Request payload: {}", s),
-            )
-        );
+    let shutdown = Shutdown::listen();
+    let metrics = install_metrics()?;
+    let breaker = Arc::new(Breaker::new(
+        cli.breaker_failures,
+        cli.breaker_successes,
+        Duration::from_secs(cli.breaker_cool_down),
+        cli.instance_id.clone(),
+    ));
 
-        let res = client
-            .request(NATS_WORKING_QUEUE, api_msg.payload.clone())
-            .await?;
+    let fallback = cli
+        .fallback
+        .then(|| Arc::new(Fallback::new(Command::default())));
+    let tracker = fallback.clone().map(|fallback| {
+        info!("Fall back on the last known command when no worker answers");
+        let client = client.clone();
 
-        trace!("Got a response from {}", NATS_WORKING_QUEUE);
-        debug!(
-            "{}",
-            from_utf8(&api_msg.payload).map_or_else(
-                |e| format!("Can't create string slice from response payload: {}", e),
-                |s| format!("Response payload: {}", s),
-            )
-        );
+        Arc::new(tokio::spawn(async move {
+            if let Err(e) = fallback.track(client).await {
+                error!("Command tracker failed: {:?}", e);
+            }
+        }))
+    });
 
-        info!("Publish the response to {}", api_msg.reply.clone().unwrap());
-        client
-            .publish(
-                api_msg
-                    .reply
-                    .ok_or_else(|| eyre::eyre!("No reply for publish found"))?,
-                res.payload,
-            )
-            .await?;
+    let mut health = Health::new()
+        .ready("nats", check::nats(client.clone()))
+        .ready("shutdown", check::shutdown(shutdown.clone()))
+        .ready(
+            "jetstream",
+            check::jetstream(jetstream::new(client.clone())),
+        )
+        .ready("workers", {
+            let breaker = Arc::clone(&breaker);
+            move || ready(breaker.check())
+        });
+    if let Some(tracker) = tracker {
+        health = health.ready("commands", check::task(tracker, "Command tracker is dead"));
     }
+    health
+        .metrics(metrics)
+        .spawn(SocketAddr::new(cli.health_ip.parse()?, cli.health_port))?;
+
+    info!(
+        "Start processor #{} in the queue group {}",
+        cli.instance_id, cli.queue_group
+    );
+    let subscription = client
+        .queue_subscribe(NATS_REQUEST_REPLY, cli.queue_group)
+        .await?;
+    let messages = shutdown::drain(subscription, shutdown.clone());
+
+    let processor = Processor {
+        upstream: client.clone(),
+        instance_id: cli.instance_id,
+        retry: Retry {
+            retries: cli.retries,
+            backoff: Duration::from_millis(cli.retry_backoff),
+            max_backoff: Duration::from_millis(cli.max_retry_backoff),
+        },
+        attempt_timeout: Duration::from_millis(cli.attempt_timeout),
+        breaker,
+        fallback,
+    };
+    info!("Process up to {} messages at a time", cli.concurrency);
+    let work = processor.dispatch(messages, cli.concurrency.into());
+
+    shutdown
+        .within(Duration::from_secs(cli.shutdown_grace_period), work)
+        .await;
+
+    info!("Flush the client");
+    client.flush().await?;
 
     Ok(())
 }
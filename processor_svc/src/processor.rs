@@ -0,0 +1,592 @@
+use std::{str::from_utf8, sync::Arc, time::Duration};
+
+use crate::breaker::Breaker;
+use crate::fallback::{Fallback, PROCESSED_BY};
+use crate::retry::Retry;
+use crate::upstream::Upstream;
+use async_nats::{HeaderMap, Message, Request, RequestError, RequestErrorKind};
+use bytes::Bytes;
+use command::header;
+use futures::{Stream, StreamExt};
+use metrics::{counter, gauge};
+use tokio::time::Instant;
+use tracing::{debug, info, info_span, trace, warn, Instrument};
+
+pub const NATS_REQUEST_REPLY: &str = "nats.request-reply";
+const NATS_WORKING_QUEUE: &str = "nats.wq";
+
+/// What became of a message
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Outcome {
+    /// The response of a worker was sent back
+    Replied,
+    /// No worker answered, the failure was sent back instead
+    Failed,
+    /// The circuit breaker is open, the failure was sent back right away
+    Rejected,
+    /// No worker answered, the processor applied the command itself
+    Fallback,
+    /// The timeout expired before the message was passed on
+    Expired,
+    /// Nothing could be sent back
+    Dropped,
+}
+
+impl Outcome {
+    fn as_str(&self) -> &'static str {
+        match self {
+            Outcome::Replied => "replied",
+            Outcome::Failed => "failed",
+            Outcome::Rejected => "rejected",
+            Outcome::Fallback => "fallback",
+            Outcome::Expired => "expired",
+            Outcome::Dropped => "dropped",
+        }
+    }
+}
+
+/// Passes messages on to workers and their responses back
+pub struct Processor<U> {
+    pub upstream: U,
+    /// ID reported in replies and metrics
+    pub instance_id: String,
+    pub retry: Retry,
+    /// Time a worker has to answer an attempt, unless less of the timeout of the message
+    /// is left
+    pub attempt_timeout: Duration,
+    pub breaker: Arc<Breaker>,
+    /// Degraded mode taking over from workers, if it's on
+    pub fallback: Option<Arc<Fallback>>,
+}
+
+/// Why no worker has answered a request
+#[derive(Debug)]
+enum Failure {
+    Request(RequestError),
+    /// The circuit breaker is open
+    Open,
+}
+
+impl<U: Upstream> Processor<U> {
+    /// Process messages as they come, at most `concurrency` at a time, so that a slow
+    /// worker holds up only the messages it has taken
+    pub async fn dispatch(&self, messages: impl Stream<Item = Message>, concurrency: usize) {
+        messages
+            .for_each_concurrent(concurrency, |api_msg| {
+                let span = info_span!(
+                    "message",
+                    request_id = api_msg
+                        .headers
+                        .as_ref()
+                        .and_then(|headers| headers.get(header::REQUEST_ID))
+                        .map_or_else(|| String::from("<none>"), ToString::to_string),
+                );
+
+                self.track(api_msg).instrument(span)
+            })
+            .await
+    }
+
+    /// Process the message and record what became of it
+    async fn track(&self, api_msg: Message) {
+        let in_flight = gauge!("processor_in_flight", "processor_id" => self.instance_id.clone());
+        in_flight.increment(1.0);
+        let outcome = self.process(api_msg).await;
+        in_flight.decrement(1.0);
+
+        debug!("Outcome: {:?}", outcome);
+        counter!(
+            "processor_messages_total",
+            "processor_id" => self.instance_id.clone(),
+            "outcome" => outcome.as_str(),
+        )
+        .increment(1);
+    }
+
+    /// Pass the message on to a worker and its response back, or the output of the fallback
+    /// or the failure if no worker has answered, unless the timeout has expired
+    async fn process(&self, api_msg: Message) -> Outcome {
+        let received = Instant::now();
+        info!("Got a message from {}", api_msg.subject);
+        debug!("Message: {:?}", api_msg);
+        debug!(
+            "{}",
+            from_utf8(&api_msg.payload).map_or_else(
+                |e| format!("Can't create string slice from request payload: {}", e),
+                |s| format!("Request payload: {}", s),
+            )
+        );
+
+        let Some(reply) = api_msg.reply.clone() else {
+            warn!("No reply subject, drop the message");
+            return Outcome::Dropped;
+        };
+
+        let (mut headers, payload, outcome) = match self.request(&api_msg, received).await {
+            Ok(Some(res)) => {
+                trace!("Got a response from {}", NATS_WORKING_QUEUE);
+                debug!(
+                    "{}",
+                    from_utf8(&res.payload).map_or_else(
+                        |e| format!("Can't create string slice from response payload: {}", e),
+                        |s| format!("Response payload: {}", s),
+                    )
+                );
+
+                (
+                    res.headers.unwrap_or_default(),
+                    res.payload,
+                    Outcome::Replied,
+                )
+            }
+            Ok(None) => return Outcome::Expired,
+            Err(failure) => match &self.fallback {
+                Some(fallback) => self.fall_back(fallback, &api_msg, failure),
+                None => failed(failure),
+            },
+        };
+        headers.insert(header::PROCESSOR_ID, self.instance_id.as_str());
+
+        info!("Publish the reply to {}", reply);
+        if let Err(e) = self.upstream.reply(reply, headers, payload).await {
+            warn!("Can't publish the reply: {}", e);
+            return Outcome::Dropped;
+        }
+
+        outcome
+    }
+
+    /// Apply the command locally instead of a worker, or reply with the failure if the
+    /// message can't be processed by the fallback either
+    fn fall_back(
+        &self,
+        fallback: &Fallback,
+        api_msg: &Message,
+        failure: Failure,
+    ) -> (HeaderMap, Bytes, Outcome) {
+        match fallback.apply(api_msg) {
+            Ok((command, output)) => {
+                info!("No worker answered, fall back on {:?}", command);
+
+                let mut headers = HeaderMap::new();
+                headers.insert(header::COMMAND, command.code());
+                headers.insert(header::WORKER_ID, self.instance_id.as_str());
+                headers.insert(header::PROCESSED_BY, PROCESSED_BY);
+
+                (headers, output.into(), Outcome::Fallback)
+            }
+            Err(e) => {
+                warn!("Can't fall back: {}", e);
+                failed(failure)
+            }
+        }
+    }
+
+    /// Request a worker, retrying while attempts are left and the backoff fits into the
+    /// timeout, if any, unless the circuit breaker is open
+    ///
+    /// Only missing workers and attempts which got the whole attempt timeout and still
+    /// weren't answered count as failures of the circuit breaker, so that short timeouts of
+    /// a caller don't open it for everyone. Gives `None` once the timeout has expired, as the caller has given up by then.
+    async fn request(
+        &self,
+        api_msg: &Message,
+        received: Instant,
+    ) -> Result<Option<Message>, Failure> {
+        let budget = api_msg
+            .headers
+            .as_ref()
+            .and_then(|headers| headers.get(header::TIMEOUT))
+            .and_then(|value| value.to_string().parse().ok())
+            .map(Duration::from_millis);
+
+        let mut attempt = 0;
+        loop {
+            let mut headers = api_msg.headers.clone().unwrap_or_default();
+            let mut timeout = self.attempt_timeout;
+
+            if let Some(budget) = budget {
+                let Some(left) = budget.checked_sub(received.elapsed()) else {
+                    warn!("Timeout of {:?} has expired, drop the message", budget);
+                    return Ok(None);
+                };
+
+                trace!("Pass on the rest of the timeout");
+                debug!("Timeout left: {:?}", left);
+                timeout = timeout.min(left);
+                headers.insert(header::TIMEOUT, timeout.as_millis().to_string().as_str());
+            }
+            let request = Request::new()
+                .payload(api_msg.payload.clone())
+                .timeout(Some(timeout));
+
+            if !self.breaker.allow() {
+                debug!("The circuit breaker is open, fail fast");
+                return Err(Failure::Open);
+            }
+
+            trace!("Send a request to {}", NATS_WORKING_QUEUE);
+            let e = match self
+                .upstream
+                .request(NATS_WORKING_QUEUE, request.headers(headers))
+                .await
+            {
+                Ok(res) => {
+                    self.breaker.success();
+                    return Ok(Some(res));
+                }
+                Err(e) => {
+                    match e.kind() {
+                        RequestErrorKind::NoResponders => self.breaker.failure(),
+                        RequestErrorKind::TimedOut if timeout >= self.attempt_timeout => {
+                            self.breaker.failure()
+                        }
+                        // A timeout cut short by the caller tells nothing about the workers
+                        _ => self.breaker.release(),
+                    }
+                    e
+                }
+            };
+
+            let delay = self.retry.delay(attempt, rand::random());
+            let fits = budget.map_or(true, |budget| received.elapsed() + delay < budget);
+            if attempt >= self.retry.retries || !fits {
+                warn!("No response from {}: {}", NATS_WORKING_QUEUE, e);
+                return Err(Failure::Request(e));
+            }
+
+            attempt += 1;
+            info!(
+                "No response from {}: {}, retry #{} in {:?}",
+                NATS_WORKING_QUEUE, e, attempt, delay
+            );
+            counter!("processor_retries_total", "processor_id" => self.instance_id.clone())
+                .increment(1);
+            tokio::time::sleep(delay).await;
+        }
+    }
+}
+
+/// Reply with the failure in headers and an empty payload
+fn failed(failure: Failure) -> (HeaderMap, Bytes, Outcome) {
+    let (status, description) = status(&failure);
+    warn!("Reply with the failure {}: {}", status, description);
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::STATUS, status.to_string().as_str());
+    headers.insert(header::DESCRIPTION, description.as_str());
+
+    let outcome = match failure {
+        Failure::Request(_) => Outcome::Failed,
+        Failure::Open => Outcome::Rejected,
+    };
+
+    (headers, Bytes::new(), outcome)
+}
+
+/// Status code and description of a failure as of [header::STATUS]
+fn status(failure: &Failure) -> (u16, String) {
+    match failure {
+        Failure::Request(e) => match e.kind() {
+            RequestErrorKind::NoResponders => (503, String::from("No worker is available")),
+            RequestErrorKind::TimedOut => (408, String::from("No worker answered in time")),
+            RequestErrorKind::Other => (500, e.to_string()),
+        },
+        Failure::Open => (503, String::from("Workers are unavailable")),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::breaker::State;
+    use async_nats::{PublishError, Subject};
+    use command::Command;
+    use futures::{future::BoxFuture, stream};
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    };
+    use tokio::sync::Semaphore;
+
+    const MESSAGES: usize = 16;
+    const LATENCY: Duration = Duration::from_millis(100);
+
+    /// Workers which take a request at a time each and answer it after [LATENCY]
+    struct Fleet {
+        workers: Semaphore,
+        /// Number of the first requests no worker answers
+        failures: AtomicUsize,
+        /// How those requests fail
+        failure: RequestErrorKind,
+        requests: AtomicUsize,
+        replies: Mutex<Vec<(HeaderMap, Bytes)>>,
+    }
+
+    impl Upstream for Fleet {
+        fn request<'a>(
+            &'a self,
+            subject: &'static str,
+            _: Request,
+        ) -> BoxFuture<'a, Result<Message, RequestError>> {
+            Box::pin(async move {
+                if self.requests.fetch_add(1, Ordering::SeqCst)
+                    < self.failures.load(Ordering::SeqCst)
+                {
+                    return Err(self.failure.into());
+                }
+
+                let _worker = self.workers.acquire().await.unwrap();
+                tokio::time::sleep(LATENCY).await;
+
+                Ok(message(subject, None, None))
+            })
+        }
+
+        fn reply(
+            &self,
+            _: Subject,
+            headers: HeaderMap,
+            payload: Bytes,
+        ) -> BoxFuture<'_, Result<(), PublishError>> {
+            self.replies.lock().unwrap().push((headers, payload));
+            Box::pin(async { Ok(()) })
+        }
+    }
+
+    fn processor(workers: usize, failures: usize) -> Processor<Fleet> {
+        Processor {
+            upstream: Fleet {
+                workers: Semaphore::new(workers),
+                failures: AtomicUsize::new(failures),
+                failure: RequestErrorKind::NoResponders,
+                requests: AtomicUsize::default(),
+                replies: Mutex::default(),
+            },
+            instance_id: String::from("processor-1"),
+            retry: Retry {
+                retries: 2,
+                backoff: Duration::from_millis(50),
+                max_backoff: Duration::from_millis(1000),
+            },
+            attempt_timeout: Duration::from_secs(1),
+            breaker: Arc::new(Breaker::new(
+                100,
+                1,
+                Duration::from_secs(10),
+                String::from("processor-1"),
+            )),
+            fallback: None,
+        }
+    }
+
+    fn message(subject: &str, reply: Option<String>, headers: Option<HeaderMap>) -> Message {
+        Message {
+            subject: subject.into(),
+            reply: reply.map(Subject::from),
+            payload: "test".into(),
+            headers,
+            status: None,
+            description: None,
+            length: 0,
+        }
+    }
+
+    fn messages(count: usize) -> impl Stream<Item = Message> {
+        stream::iter(
+            (0..count).map(|i| message(NATS_REQUEST_REPLY, Some(format!("inbox.{}", i)), None)),
+        )
+    }
+
+    /// Time it takes the fleet to answer all the messages
+    async fn elapsed(workers: usize, concurrency: usize) -> Duration {
+        let processor = processor(workers, 0);
+
+        let start = Instant::now();
+        processor.dispatch(messages(MESSAGES), concurrency).await;
+        let elapsed = start.elapsed();
+
+        assert_eq!(processor.upstream.replies.lock().unwrap().len(), MESSAGES);
+        elapsed
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throughput_scales_with_workers() {
+        assert_eq!(elapsed(1, MESSAGES).await, LATENCY * 16);
+        assert_eq!(elapsed(4, MESSAGES).await, LATENCY * 4);
+        assert_eq!(elapsed(16, MESSAGES).await, LATENCY);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn in_flight_messages_are_bounded() {
+        assert_eq!(elapsed(16, 1).await, LATENCY * 16);
+        assert_eq!(elapsed(16, 4).await, LATENCY * 4);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_tell_the_processor() {
+        let processor = processor(1, 0);
+        processor.dispatch(messages(MESSAGES), 1).await;
+
+        for (headers, _) in processor.upstream.replies.lock().unwrap().iter() {
+            assert_eq!(
+                headers.get(header::PROCESSOR_ID).unwrap().as_str(),
+                "processor-1"
+            );
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_until_a_worker_answers() {
+        let processor = processor(1, 2);
+        processor.dispatch(messages(1), 1).await;
+
+        assert_eq!(processor.upstream.requests.load(Ordering::SeqCst), 3);
+        let replies = processor.upstream.replies.lock().unwrap();
+        assert_eq!(replies[0].1, "test");
+        assert!(replies[0].0.get(header::STATUS).is_none());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn failure_is_replied_once_retries_run_out() {
+        let processor = processor(1, usize::MAX);
+        processor.dispatch(messages(1), 1).await;
+
+        assert_eq!(processor.upstream.requests.load(Ordering::SeqCst), 3);
+        let replies = processor.upstream.replies.lock().unwrap();
+        let (headers, payload) = &replies[0];
+        assert_eq!(headers.get(header::STATUS).unwrap().as_str(), "503");
+        assert_eq!(
+            headers.get(header::DESCRIPTION).unwrap().as_str(),
+            "No worker is available"
+        );
+        assert!(payload.is_empty());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_fit_into_the_timeout() {
+        let processor = processor(1, usize::MAX);
+        let mut headers = HeaderMap::new();
+        // Shorter than the first backoff
+        headers.insert(header::TIMEOUT, "20");
+        let message = message(NATS_REQUEST_REPLY, Some("inbox".into()), Some(headers));
+
+        processor.dispatch(stream::iter([message]), 1).await;
+
+        assert_eq!(processor.upstream.requests.load(Ordering::SeqCst), 1);
+        let replies = processor.upstream.replies.lock().unwrap();
+        assert_eq!(replies[0].0.get(header::STATUS).unwrap().as_str(), "503");
+    }
+
+    #[tokio::test]
+    async fn messages_without_reply_subject_are_dropped() {
+        let processor = processor(1, 0);
+        let outcome = processor
+            .process(message(NATS_REQUEST_REPLY, None, None))
+            .await;
+
+        assert_eq!(outcome, Outcome::Dropped);
+        assert_eq!(processor.upstream.requests.load(Ordering::SeqCst), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn fails_fast_while_the_circuit_is_open() {
+        let processor = Processor {
+            breaker: Arc::new(Breaker::new(
+                1,
+                1,
+                Duration::from_secs(10),
+                String::from("processor-1"),
+            )),
+            ..processor(1, usize::MAX)
+        };
+        processor.dispatch(messages(2), 1).await;
+
+        // The first failure opens the circuit, which stops the retries as well
+        assert_eq!(processor.upstream.requests.load(Ordering::SeqCst), 1);
+        let replies = processor.upstream.replies.lock().unwrap();
+        assert_eq!(replies.len(), 2);
+        for (headers, _) in replies.iter() {
+            assert_eq!(headers.get(header::STATUS).unwrap().as_str(), "503");
+        }
+        assert_eq!(
+            replies[1].0.get(header::DESCRIPTION).unwrap().as_str(),
+            "Workers are unavailable"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn timeouts_of_callers_leave_the_circuit_closed() {
+        let mut processor = Processor {
+            breaker: Arc::new(Breaker::new(
+                1,
+                1,
+                Duration::from_secs(10),
+                String::from("processor-1"),
+            )),
+            ..processor(1, usize::MAX)
+        };
+        processor.upstream.failure = RequestErrorKind::TimedOut;
+        let mut headers = HeaderMap::new();
+        // Shorter than the attempt timeout
+        headers.insert(header::TIMEOUT, "20");
+        let message = message(NATS_REQUEST_REPLY, Some("inbox".into()), Some(headers));
+
+        processor.dispatch(stream::iter([message]), 1).await;
+        assert_eq!(processor.breaker.state(), State::Closed);
+
+        processor.dispatch(messages(1), 1).await;
+        assert_eq!(processor.breaker.state(), State::Open);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn falls_back_on_the_last_known_command() {
+        let processor = Processor {
+            fallback: Some(Arc::new(Fallback::new(Command::Reverse))),
+            ..processor(1, usize::MAX)
+        };
+        let mut headers = HeaderMap::new();
+        headers.insert(header::COMMAND, Command::ToUpperCase.code());
+        let overridden = message(NATS_REQUEST_REPLY, Some("inbox".into()), Some(headers));
+        let messages = messages(1).chain(stream::iter([overridden]));
+
+        processor.dispatch(messages, 1).await;
+
+        let replies = processor.upstream.replies.lock().unwrap();
+        let outputs = replies.iter().map(|(headers, payload)| {
+            assert!(headers.get(header::STATUS).is_none());
+            assert_eq!(
+                headers.get(header::PROCESSED_BY).unwrap().as_str(),
+                "fallback"
+            );
+            assert_eq!(
+                headers.get(header::WORKER_ID).unwrap().as_str(),
+                "processor-1"
+            );
+            (
+                headers.get(header::COMMAND).unwrap().to_string(),
+                payload.clone(),
+            )
+        });
+        assert_eq!(
+            outputs.collect::<Vec<_>>(),
+            [
+                (String::from("rev"), Bytes::from("tset")),
+                (String::from("uc"), Bytes::from("TEST")),
+            ]
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn replies_of_workers_are_not_marked() {
+        let processor = Processor {
+            fallback: Some(Arc::new(Fallback::new(Command::Reverse))),
+            ..processor(1, 0)
+        };
+        processor.dispatch(messages(1), 1).await;
+
+        let replies = processor.upstream.replies.lock().unwrap();
+        assert_eq!(replies[0].1, "test");
+        assert!(replies[0].0.get(header::PROCESSED_BY).is_none());
+    }
+}
@@ -0,0 +1,52 @@
+use std::time::Duration;
+
+/// How a request no worker has answered is retried
+#[derive(Debug, Clone, Copy)]
+pub struct Retry {
+    /// Times a request is retried after the first attempt
+    pub retries: u32,
+    /// Backoff before the first retry, doubled for every next one
+    pub backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl Retry {
+    /// Delay before the retry following the given attempt, counting from 0
+    ///
+    /// The delay is between a half and the whole of the backoff as of `jitter` between 0
+    /// and 1, so that requests which failed together don't retry together.
+    pub fn delay(&self, attempt: u32, jitter: f64) -> Duration {
+        let backoff = self
+            .backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+
+        backoff.mul_f64(0.5 + jitter.clamp(0.0, 1.0) / 2.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RETRY: Retry = Retry {
+        retries: 5,
+        backoff: Duration::from_millis(100),
+        max_backoff: Duration::from_millis(500),
+    };
+
+    #[test]
+    fn exponential_backoff() {
+        let delays = (0..5).map(|attempt| RETRY.delay(attempt, 1.0).as_millis());
+        assert_eq!(delays.collect::<Vec<_>>(), [100, 200, 400, 500, 500]);
+
+        assert_eq!(RETRY.delay(u32::MAX, 1.0), RETRY.max_backoff);
+    }
+
+    #[test]
+    fn jitter() {
+        assert_eq!(RETRY.delay(1, 0.0), Duration::from_millis(100));
+        assert_eq!(RETRY.delay(1, 0.5), Duration::from_millis(150));
+        assert_eq!(RETRY.delay(1, 1.0), Duration::from_millis(200));
+    }
+}
@@ -0,0 +1,39 @@
+use async_nats::{Client, HeaderMap, Message, PublishError, Request, RequestError, Subject};
+use bytes::Bytes;
+use futures::future::BoxFuture;
+
+/// The side of NATS messages are passed on to workers by and their responses sent back
+pub trait Upstream: Send + Sync {
+    fn request<'a>(
+        &'a self,
+        subject: &'static str,
+        request: Request,
+    ) -> BoxFuture<'a, Result<Message, RequestError>>;
+
+    /// Publish a reply to the subject a message is waiting for it on
+    fn reply(
+        &self,
+        subject: Subject,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> BoxFuture<'_, Result<(), PublishError>>;
+}
+
+impl Upstream for Client {
+    fn request<'a>(
+        &'a self,
+        subject: &'static str,
+        request: Request,
+    ) -> BoxFuture<'a, Result<Message, RequestError>> {
+        Box::pin(self.send_request(subject, request))
+    }
+
+    fn reply(
+        &self,
+        subject: Subject,
+        headers: HeaderMap,
+        payload: Bytes,
+    ) -> BoxFuture<'_, Result<(), PublishError>> {
+        Box::pin(self.publish_with_headers(subject, headers, payload))
+    }
+}
@@ -1,3 +1,5 @@
+use std::net::Ipv4Addr;
+
 use clap::Parser;
 
 #[derive(Parser)]
@@ -23,6 +25,24 @@ pub struct Cli {
     )]
     pub worker_id: String,
 
+    #[clap(
+        long,
+        env = "HEALTH_IP",
+        default_value_t = Ipv4Addr::UNSPECIFIED.to_string()
+    )]
+    pub health_ip: String,
+
+    #[clap(
+        long,
+        env = "HEALTH_PORT",
+        default_value_t = 3002
+    )]
+    pub health_port: u16,
+
+    /// Seconds given to in-flight work to finish once shutdown is requested
+    #[clap(long, env = "SHUTDOWN_GRACE_PERIOD", default_value_t = 30)]
+    pub shutdown_grace_period: u64,
+
     #[clap(flatten)]
     pub instrumentation: instrumentation::Instrumentation,
 }
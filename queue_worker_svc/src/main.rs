@@ -1,19 +1,34 @@
 mod cli;
 
-use std::{str::from_utf8, sync::Arc};
+use std::{
+    net::SocketAddr,
+    str::from_utf8,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 
 use crate::cli::Cli;
-use async_nats::{jetstream, Client, ServerAddr};
+use async_nats::{
+    jetstream::{self, stream::LastRawMessageErrorKind},
+    Client, HeaderMap, Message, ServerAddr,
+};
 use clap::Parser;
-use color_eyre::{eyre, Result};
-use command::Command;
+use color_eyre::{eyre::WrapErr, Result};
+use command::{header, Command};
 use futures::StreamExt;
-use tokio::sync::RwLock;
-use tracing::{debug, info, trace};
+use health::{check, Health};
+use shutdown::Shutdown;
+use streams::audit::Event;
+use tokio::{sync::RwLock, task::JoinHandle};
+use tracing::{debug, info, info_span, trace, warn, Instrument};
 use url::Url;
 
 const NATS_WQ: &str = "nats.wq";
 const NATS_QUEUE_GROUP: &str = "QUEUE_WORKERS";
+const NATS_WORKERS_COMMAND: &str = "nats.workers.command";
 
 #[tokio::main]
 async fn main() -> Result<()> {
@@ -28,111 +43,399 @@ async fn main() -> Result<()> {
     debug!("Info: {:#?}", addr);
     let client = async_nats::connect(addr).await?;
 
-    let mut subscribtion = client
+    let subscribtion = client
         .queue_subscribe(NATS_WQ, NATS_QUEUE_GROUP.into())
         .await?;
 
+    let shutdown = Shutdown::listen();
+
     let rw_command = Arc::new(RwLock::new(Command::default()));
     let worker_id = Arc::new(cli.worker_id);
+    let sequence = Arc::new(AtomicU64::new(0));
+    let attached = Arc::new(AtomicBool::new(false));
 
-    spawn_command_processor(
+    let command_processor = spawn_command_processor(
         client.clone(),
         Arc::clone(&rw_command),
+        Arc::clone(&sequence),
         Arc::clone(&worker_id),
+        Arc::clone(&attached),
     )
     .await;
 
+    spawn_command_reporter(
+        client.clone(),
+        Arc::clone(&rw_command),
+        Arc::clone(&sequence),
+        Arc::clone(&worker_id),
+    )
+    .await?;
+
+    Health::new()
+        .live(
+            "command-processor",
+            check::task(Arc::new(command_processor), "Command processor is dead"),
+        )
+        .ready("nats", check::nats(client.clone()))
+        .ready("shutdown", check::shutdown(shutdown.clone()))
+        .ready(
+            "jetstream",
+            check::jetstream(jetstream::new(client.clone())),
+        )
+        .ready(
+            "commands-consumer",
+            check::flag(attached, "Consumer of COMMANDS is not attached"),
+        )
+        .spawn(SocketAddr::new(cli.health_ip.parse()?, cli.health_port))?;
+
     info!("Start worker #{}", worker_id);
     debug!(
         "Subscribed to '{}' with group '{}'",
         NATS_WQ, NATS_QUEUE_GROUP
     );
 
-    while let Some(msg) = subscribtion.next().await {
-        info!("Got a request from {}", msg.subject);
-        debug!("Request: {:?}", msg);
+    let mut messages = shutdown::drain(subscribtion, shutdown.clone());
 
-        trace!("Process message");
-        debug!(
-            "{}",
-            from_utf8(&msg.payload).map_or_else(
-                |e| format!("Can't create string slice from message payload: {}", e),
-                |s| format!("Message: {}", s),
-            )
-        );
+    let work = async {
+        while let Some(msg) = messages.next().await {
+            let request_id = msg
+                .headers
+                .as_ref()
+                .and_then(|headers| headers.get(header::REQUEST_ID))
+                .map(ToString::to_string);
+            let span = info_span!(
+                "message",
+                request_id = request_id.as_deref().unwrap_or("<none>"),
+            );
 
-        let res = rw_command
-            .read()
-            .await
-            .call_on(from_utf8(&msg.payload)?.to_string());
+            // A message which can't be answered must not stop the worker
+            if let Err(e) = process(&client, &rw_command, &worker_id, request_id, msg)
+                .instrument(span)
+                .await
+            {
+                warn!("Can't reply: {:?}", e);
+            }
+        }
+    };
 
-        trace!("Got a result");
-        debug!("Result {}", res);
+    shutdown
+        .within(Duration::from_secs(cli.shutdown_grace_period), work)
+        .await;
 
-        info!("Publish the result to {}", msg.reply.clone().unwrap());
-        client
-            .publish(
-                msg.reply
-                    .ok_or_else(|| eyre::eyre!("No reply for publish found"))?,
-                res.into(),
-            )
-            .await?;
+    info!("Flush the client");
+    client.flush().await?;
+
+    Ok(())
+}
+
+/// Apply the command to the message and reply with the result, or with the failure in
+/// [header::STATUS] and [header::DESCRIPTION] if the message can't be processed
+async fn process(
+    client: &Client,
+    rw_command: &RwLock<Command>,
+    worker_id: &str,
+    request_id: Option<String>,
+    msg: Message,
+) -> Result<()> {
+    info!("Got a request from {}", msg.subject);
+    debug!("Request: {:?}", msg);
+
+    let Some(reply) = msg.reply.clone() else {
+        warn!("No reply subject, drop the message");
+        return Ok(());
+    };
+
+    let mut headers = HeaderMap::new();
+    headers.insert(header::WORKER_ID, worker_id);
+    if let Some(request_id) = request_id {
+        headers.insert(header::REQUEST_ID, request_id.as_str());
     }
 
+    let active = *rw_command.read().await;
+    let payload = match apply(&msg, active) {
+        Ok((command, res)) => {
+            trace!("Got a result");
+            debug!("Result {}", res);
+
+            headers.insert(header::COMMAND, command.code());
+            res.into()
+        }
+        Err(e) => {
+            warn!("Can't process the message: {}", e);
+
+            headers.insert(header::STATUS, "400");
+            headers.insert(header::DESCRIPTION, e.to_string().as_str());
+            Default::default()
+        }
+    };
+
+    info!("Publish the reply to {}", reply);
+    client.publish_with_headers(reply, headers, payload).await?;
+
     Ok(())
 }
 
+/// Apply the command of the `Command` header, if any, or else the active one to the payload
+fn apply(msg: &Message, active: Command) -> Result<(Command, String)> {
+    trace!("Process message");
+    debug!(
+        "{}",
+        from_utf8(&msg.payload).map_or_else(
+            |e| format!("Can't create string slice from message payload: {}", e),
+            |s| format!("Message: {}", s),
+        )
+    );
+
+    let command = match msg.headers.as_ref().and_then(|h| h.get(header::COMMAND)) {
+        Some(code) => {
+            trace!("Apply command from header");
+            code.to_string().try_into()?
+        }
+        None => active,
+    };
+    debug!("Command: {:?}", command);
+
+    let input = from_utf8(&msg.payload).wrap_err("Payload is not UTF-8")?;
+
+    Ok((command, command.call_on(input.to_string())))
+}
+
 async fn spawn_command_processor(
     client: Client,
     rw_command: Arc<RwLock<Command>>,
+    sequence: Arc<AtomicU64>,
     worker_id: Arc<String>,
-) {
+    attached: Arc<AtomicBool>,
+) -> JoinHandle<Result<(), async_nats::Error>> {
     info!("Start command processor");
 
     tokio::spawn(async move {
         let jetstream = jetstream::new(client);
 
         let mut stream = jetstream
-            .create_stream(jetstream::stream::Config {
-                name: "COMMANDS".to_string(),
-                retention: jetstream::stream::RetentionPolicy::Limits,
-                subjects: vec!["nats.fnf".to_string()],
-                max_messages: 1,
-                ..Default::default()
-            })
+            .get_or_create_stream(streams::commands::config())
             .await?;
         trace!("Stream created");
         debug!("Stream info: {:#?}", stream.info().await?);
 
+        jetstream
+            .get_or_create_stream(streams::audit::config())
+            .await?;
+        trace!("Audit stream created");
+
         let mut consumer = stream
             .create_consumer(jetstream::consumer::pull::Config {
                 durable_name: Some(format!("processor-fnf-{}", worker_id)),
+                // Only the active command matters, not the ones it has replaced
+                deliver_policy: jetstream::consumer::DeliverPolicy::Last,
                 ..Default::default()
             })
             .await?;
         trace!("Consumer created");
         debug!("Consumer info: {:#?}", consumer.info().await?);
 
+        // The consumer resumes after the commands acked before a restart, so the active
+        // one is taken from the stream
+        match stream
+            .get_last_raw_message_by_subject(streams::commands::SUBJECT)
+            .await
+        {
+            Ok(raw) => {
+                let msg_sequence = raw.sequence;
+                let msg = Message::try_from(raw)?;
+                let msg_command = serde_json::from_slice::<Command>(&msg.payload)?;
+                apply_command(
+                    &jetstream,
+                    &rw_command,
+                    &sequence,
+                    &worker_id,
+                    msg_command,
+                    msg_sequence,
+                )
+                .await;
+            }
+            Err(e) if e.kind() == LastRawMessageErrorKind::NoMessageFound => {
+                trace!("No command stored yet");
+            }
+            Err(e) => return Err(e.into()),
+        }
+
+        attached.store(true, Ordering::Release);
+
         info!("Processor-fnf spawned");
 
-        while let Some(Ok(msg)) = consumer.messages().await?.next().await {
+        let mut messages = consumer.messages().await?;
+
+        while let Some(Ok(msg)) = messages.next().await {
             info!("Processor-fnf got a message");
             debug!("Message: {:?}", msg);
 
-            let mut command = rw_command.write().await;
             let msg_command = serde_json::from_slice::<Command>(&msg.payload)?;
-            if command.ne(&msg_command) {
-                trace!("Update command");
-                debug!("Old: {:?}, new: {:?}", command, msg_command);
+            let msg_sequence = msg.info()?.stream_sequence;
+            apply_command(
+                &jetstream,
+                &rw_command,
+                &sequence,
+                &worker_id,
+                msg_command,
+                msg_sequence,
+            )
+            .await;
 
-                *command = msg_command;
-            } else {
-                trace!("No update needed");
+            // Otherwise the command would be delivered again once the ack wait is over
+            if let Err(e) = msg.ack().await {
+                warn!("Can't ack the command: {}", e);
             }
 
             info!("Processor-fnf processed the message");
         }
 
+        Ok::<(), async_nats::Error>(())
+    })
+}
+
+/// Hold the command stored at the sequence unless a later one is held already, and audit
+/// a change of the command
+async fn apply_command(
+    jetstream: &jetstream::Context,
+    rw_command: &RwLock<Command>,
+    sequence: &AtomicU64,
+    worker_id: &str,
+    msg_command: Command,
+    msg_sequence: u64,
+) {
+    let mut command = rw_command.write().await;
+    if msg_sequence <= sequence.load(Ordering::Acquire) {
+        debug!("Skip the command of sequence {}", msg_sequence);
+        return;
+    }
+    sequence.store(msg_sequence, Ordering::Release);
+
+    if command.eq(&msg_command) {
+        trace!("No update needed");
+        return;
+    }
+
+    trace!("Update command");
+    debug!("Old: {:?}, new: {:?}", command, msg_command);
+    let old = std::mem::replace(&mut *command, msg_command);
+    drop(command);
+
+    trace!("Audit the update");
+    let event = Event::Applied {
+        worker_id: worker_id.to_string(),
+        old,
+        new: msg_command,
+        sequence: msg_sequence,
+    };
+    if let Err(e) = streams::audit::publish(jetstream, event).await {
+        warn!("Can't audit the update: {:?}", e);
+    }
+}
+
+/// Answer with the command the worker holds to anyone asking on `NATS_WORKERS_COMMAND`
+async fn spawn_command_reporter(
+    client: Client,
+    rw_command: Arc<RwLock<Command>>,
+    sequence: Arc<AtomicU64>,
+    worker_id: Arc<String>,
+) -> Result<()> {
+    info!("Start command reporter");
+
+    let mut subscription = client.subscribe(NATS_WORKERS_COMMAND).await?;
+
+    tokio::spawn(async move {
+        while let Some(msg) = subscription.next().await {
+            trace!("Command reporter got a request");
+
+            let Some(reply) = msg.reply else {
+                continue;
+            };
+
+            let report = serde_json::json!({
+                "worker_id": worker_id.as_str(),
+                "command": rw_command.read().await.code(),
+                "sequence": match sequence.load(Ordering::Acquire) {
+                    0 => None,
+                    sequence => Some(sequence),
+                },
+            });
+            debug!("Report: {}", report);
+
+            client
+                .publish(reply, serde_json::to_vec(&report)?.into())
+                .await?;
+        }
+
         Ok::<(), async_nats::Error>(())
     });
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(payload: &[u8], command: Option<&str>) -> Message {
+        let headers = command.map(|command| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::COMMAND, command);
+            headers
+        });
+
+        Message {
+            subject: NATS_WQ.into(),
+            reply: Some("reply".into()),
+            payload: payload.to_vec().into(),
+            headers,
+            status: None,
+            description: None,
+            length: payload.len(),
+        }
+    }
+
+    #[tokio::test]
+    async fn older_and_unchanged_commands_are_skipped() {
+        // Nothing is audited, so no server is needed
+        let client = async_nats::ConnectOptions::new()
+            .retry_on_initial_connect()
+            .connect("127.0.0.1:1")
+            .await
+            .unwrap();
+        let jetstream = jetstream::new(client);
+        let rw_command = RwLock::new(Command::Capitalize);
+        let sequence = AtomicU64::new(7);
+
+        apply_command(&jetstream, &rw_command, &sequence, "1", Command::Reverse, 5).await;
+        assert_eq!(*rw_command.read().await, Command::Capitalize);
+        assert_eq!(sequence.load(Ordering::Acquire), 7);
+
+        apply_command(
+            &jetstream,
+            &rw_command,
+            &sequence,
+            "1",
+            Command::Capitalize,
+            8,
+        )
+        .await;
+        assert_eq!(*rw_command.read().await, Command::Capitalize);
+        assert_eq!(sequence.load(Ordering::Acquire), 8);
+    }
+
+    #[test]
+    fn applies_commands() {
+        let (command, output) = apply(&message(b"abc", None), Command::Reverse).unwrap();
+        assert_eq!((command, output.as_str()), (Command::Reverse, "cba"));
+
+        let (command, output) = apply(&message(b"abc", Some("uc")), Command::Reverse).unwrap();
+        assert_eq!((command, output.as_str()), (Command::ToUpperCase, "ABC"));
+    }
+
+    #[test]
+    fn malformed_messages_fail() {
+        assert!(apply(&message(b"abc", Some("unknown")), Command::Reverse).is_err());
+        assert!(apply(&message(&[0xff, 0xfe], None), Command::Reverse).is_err());
+    }
 }
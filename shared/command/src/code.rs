@@ -0,0 +1,21 @@
+//! (De)serialize a [Command] by its code, to be used with `#[serde(with = "command::code")]`
+
+use serde::{de, Deserialize, Deserializer, Serializer};
+
+use crate::Command;
+
+pub fn serialize<S>(command: &Command, serializer: S) -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.serialize_str(command.code())
+}
+
+pub fn deserialize<'de, D>(deserializer: D) -> Result<Command, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    String::deserialize(deserializer)?
+        .try_into()
+        .map_err(de::Error::custom)
+}
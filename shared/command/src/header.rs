@@ -0,0 +1,22 @@
+//! Names of NATS headers passed along with a message on its way to a worker and back
+
+/// Code of a command: an override of the one a worker holds on the way to the worker,
+/// the command which has actually been applied on the way back
+pub const COMMAND: &str = "Command";
+/// ID of the worker which processed the message
+pub const WORKER_ID: &str = "Worker-Id";
+/// ID of the processor which passed the message on to the worker
+pub const PROCESSOR_ID: &str = "Processor-Id";
+/// ID of the HTTP request the message serves, to correlate logs of every hop
+pub const REQUEST_ID: &str = "Request-Id";
+/// Milliseconds left to answer the request, every hop passes on what is left of them
+pub const TIMEOUT: &str = "Timeout";
+/// Status code of a failure the processor replies with instead of a response: `503` if no
+/// worker is available, `408` if none answered in time, `500` for anything else. A worker
+/// replies with `400` to a message it can't process
+pub const STATUS: &str = "Status";
+/// What the failure was, for people to read
+pub const DESCRIPTION: &str = "Description";
+/// Who processed the message instead of a worker: `fallback` if the processor applied the
+/// last known command itself as no worker answered
+pub const PROCESSED_BY: &str = "Processed-By";
@@ -1,26 +1,52 @@
+pub mod code;
+pub mod header;
+
 use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-#[derive(Debug, Serialize, Deserialize, PartialEq)]
+#[derive(Debug, Default, Clone, Copy, Serialize, Deserialize, PartialEq)]
 pub enum Command {
+    #[default]
     Capitalize,
     Reverse,
     ToLowerCase,
     ToUpperCase,
 }
 
-impl Default for Command {
-    fn default() -> Self {
-        Command::Capitalize
+impl Command {
+    pub const ALL: [Command; 4] = [
+        Command::Capitalize,
+        Command::Reverse,
+        Command::ToLowerCase,
+        Command::ToUpperCase,
+    ];
+
+    /// Short name the command is addressed by
+    pub fn code(&self) -> &'static str {
+        match self {
+            Command::Capitalize => "cap",
+            Command::Reverse => "rev",
+            Command::ToLowerCase => "lc",
+            Command::ToUpperCase => "uc",
+        }
     }
-}
 
-impl Command {
-    pub fn call_on(&self, mut input: String) -> String {
+    /// What the command does to a text
+    pub fn description(&self) -> &'static str {
+        match self {
+            Command::Capitalize => "Make the first letter uppercase",
+            Command::Reverse => "Reverse the order of the characters",
+            Command::ToLowerCase => "Make every letter lowercase",
+            Command::ToUpperCase => "Make every letter uppercase",
+        }
+    }
+
+    pub fn call_on(&self, input: String) -> String {
         match self {
             Command::Capitalize => {
-                if input.chars().nth(0).is_some_and(|c| !c.is_uppercase()) {
-                    let (first, rest) = input.split_at_mut(1);
+                if let Some(first) = input.chars().next().filter(|c| !c.is_uppercase()) {
+                    // The first character may take more than a byte
+                    let (first, rest) = input.split_at(first.len_utf8());
 
                     let mut capitalized = first.to_uppercase();
                     capitalized.push_str(rest);
@@ -34,7 +60,6 @@ impl Command {
                 let len = input.len();
                 input
                     .chars()
-                    .into_iter()
                     .rev()
                     .fold(String::with_capacity(len), |mut s, c| {
                         s.push(c);
@@ -74,36 +99,33 @@ impl TryInto<Command> for String {
 #[cfg(test)]
 mod tests {
     use super::*;
-    type DataType<'a> = [&'a str; 5];
+    type DataType<'a> = [&'a str; 7];
 
-    const ACTUAL: &DataType = &["", "1234qQ", "qwerty", "Qwerty", "QwErTy"];
+    const ACTUAL: &DataType = &["", "1234qQ", "qwerty", "Qwerty", "QwErTy", "élan", "ßa"];
 
     fn test(command: Command, actual: &DataType) {
         let (expected, command): (DataType, _) = match command {
             Command::Capitalize => (
-                ["", "1234qQ", "Qwerty", "Qwerty", "QwErTy"],
+                ["", "1234qQ", "Qwerty", "Qwerty", "QwErTy", "Élan", "SSa"],
                 Command::Capitalize,
             ),
             Command::Reverse => (
-                ["", "Qq4321", "ytrewq", "ytrewQ", "yTrEwQ"],
+                ["", "Qq4321", "ytrewq", "ytrewQ", "yTrEwQ", "nalé", "aß"],
                 Command::Reverse,
             ),
             Command::ToLowerCase => (
-                ["", "1234qq", "qwerty", "qwerty", "qwerty"],
+                ["", "1234qq", "qwerty", "qwerty", "qwerty", "élan", "ßa"],
                 Command::ToLowerCase,
             ),
             Command::ToUpperCase => (
-                ["", "1234QQ", "QWERTY", "QWERTY", "QWERTY"],
+                ["", "1234QQ", "QWERTY", "QWERTY", "QWERTY", "ÉLAN", "SSA"],
                 Command::ToUpperCase,
             ),
         };
 
-        actual
-            .into_iter()
-            .zip(expected)
-            .for_each(|(actual, expected)| {
-                assert_eq!(command.call_on(actual.to_string()), expected.to_string())
-            });
+        actual.iter().zip(expected).for_each(|(actual, expected)| {
+            assert_eq!(command.call_on(actual.to_string()), expected.to_string())
+        });
     }
 
     #[test]
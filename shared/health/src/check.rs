@@ -0,0 +1,73 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use async_nats::{connection::State, jetstream, Client};
+use futures::future::{ready, BoxFuture, FutureExt, Ready};
+use shutdown::Shutdown;
+use tokio::task::JoinHandle;
+
+/// Passes while the client is connected to NATS
+pub fn nats(client: Client) -> impl Fn() -> Ready<Result<(), String>> {
+    move || {
+        ready(match client.connection_state() {
+            State::Connected => Ok(()),
+            state => Err(format!("NATS connection is {}", state)),
+        })
+    }
+}
+
+/// Passes while JetStream is enabled and reachable for the account
+pub fn jetstream(
+    jetstream: jetstream::Context,
+) -> impl Fn() -> BoxFuture<'static, Result<(), String>> {
+    move || {
+        let jetstream = jetstream.clone();
+
+        async move {
+            jetstream
+                .query_account()
+                .await
+                .map(|_| ())
+                .map_err(|e| format!("JetStream is unavailable: {}", e))
+        }
+        .boxed()
+    }
+}
+
+/// Passes while the flag is raised
+pub fn flag(flag: Arc<AtomicBool>, error: &'static str) -> impl Fn() -> Ready<Result<(), String>> {
+    move || {
+        ready(if flag.load(Ordering::Acquire) {
+            Ok(())
+        } else {
+            Err(error.to_string())
+        })
+    }
+}
+
+/// Passes while the task is running
+pub fn task<T>(
+    handle: Arc<JoinHandle<T>>,
+    error: &'static str,
+) -> impl Fn() -> Ready<Result<(), String>> {
+    move || {
+        ready(if handle.is_finished() {
+            Err(error.to_string())
+        } else {
+            Ok(())
+        })
+    }
+}
+
+/// Passes until shutdown is requested, so that no new traffic comes in while draining
+pub fn shutdown(shutdown: Shutdown) -> impl Fn() -> Ready<Result<(), String>> {
+    move || {
+        ready(if shutdown.is_requested() {
+            Err("Shutting down".to_string())
+        } else {
+            Ok(())
+        })
+    }
+}
@@ -0,0 +1,230 @@
+pub mod check;
+
+use std::{collections::BTreeMap, future::Future, net::SocketAddr, sync::Arc};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use color_eyre::Result;
+use futures::{
+    future::{join_all, BoxFuture},
+    FutureExt,
+};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+use serde::Serialize;
+use tracing::{debug, error, info, trace};
+
+type Probe = Box<dyn Fn() -> BoxFuture<'static, Result<(), String>> + Send + Sync>;
+
+/// Liveness and readiness probes of a service, along with its metrics
+#[derive(Default)]
+pub struct Health {
+    liveness: Vec<(&'static str, Probe)>,
+    readiness: Vec<(&'static str, Probe)>,
+    metrics: Option<PrometheusHandle>,
+}
+
+/// Install a Prometheus recorder of the metrics of the service
+pub fn install_metrics() -> Result<PrometheusHandle> {
+    trace!("Install the metrics recorder");
+    Ok(PrometheusBuilder::new().install_recorder()?)
+}
+
+#[derive(Serialize, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Pass,
+    Fail,
+}
+
+#[derive(Serialize)]
+struct CheckReport {
+    status: Status,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+struct Report {
+    status: Status,
+    checks: BTreeMap<&'static str, CheckReport>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a check which fails when the service must be restarted
+    ///
+    /// Liveness checks are part of readiness as well.
+    pub fn live<F, Fut>(mut self, name: &'static str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.liveness
+            .push((name, Box::new(move || check().boxed())));
+        self
+    }
+
+    /// Add a check which fails when the service can't serve traffic
+    pub fn ready<F, Fut>(mut self, name: &'static str, check: F) -> Self
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), String>> + Send + 'static,
+    {
+        self.readiness
+            .push((name, Box::new(move || check().boxed())));
+        self
+    }
+
+    /// Serve the metrics recorded by the handle as well
+    pub fn metrics(mut self, handle: PrometheusHandle) -> Self {
+        self.metrics = Some(handle);
+        self
+    }
+
+    /// Routes serving `/healthz` (liveness), `/readyz` (readiness) and, if there are
+    /// metrics, `/metrics` in the Prometheus text format
+    pub fn router<S>(self) -> Router<S>
+    where
+        S: Clone + Send + Sync + 'static,
+    {
+        let mut router = Router::new()
+            .route("/healthz", get(healthz))
+            .route("/readyz", get(readyz));
+
+        if self.metrics.is_some() {
+            router = router.route("/metrics", get(metrics));
+        }
+
+        router.with_state(Arc::new(self))
+    }
+
+    /// Serve the probes on a dedicated listener in the background
+    ///
+    /// Fails right away if the address can't be bound, a server failing later is logged.
+    pub fn spawn(self, addr: SocketAddr) -> Result<()> {
+        let server = axum::Server::try_bind(&addr)?.serve(self.router::<()>().into_make_service());
+        info!("Start health server on {}", addr);
+
+        tokio::spawn(async move {
+            if let Err(e) = server.await {
+                error!("Health server failed: {}", e);
+            }
+        });
+
+        Ok(())
+    }
+}
+
+async fn healthz(State(health): State<Arc<Health>>) -> (StatusCode, Json<Report>) {
+    trace!("Check liveness");
+    run(health.liveness.iter()).await
+}
+
+async fn readyz(State(health): State<Arc<Health>>) -> (StatusCode, Json<Report>) {
+    trace!("Check readiness");
+    run(health.liveness.iter().chain(health.readiness.iter())).await
+}
+
+async fn metrics(State(health): State<Arc<Health>>) -> String {
+    trace!("Render metrics");
+    health
+        .metrics
+        .as_ref()
+        .map(PrometheusHandle::render)
+        .unwrap_or_default()
+}
+
+async fn run<'a>(
+    probes: impl Iterator<Item = &'a (&'static str, Probe)>,
+) -> (StatusCode, Json<Report>) {
+    let (names, checks): (Vec<_>, Vec<_>) = probes.map(|(name, probe)| (*name, probe())).unzip();
+
+    let checks = names
+        .into_iter()
+        .zip(join_all(checks).await)
+        .map(|(name, result)| {
+            debug!("Check '{}': {:?}", name, result);
+
+            let report = match result {
+                Ok(()) => CheckReport {
+                    status: Status::Pass,
+                    error: None,
+                },
+                Err(e) => CheckReport {
+                    status: Status::Fail,
+                    error: Some(e),
+                },
+            };
+
+            (name, report)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let (code, status) = if checks.values().all(|check| check.status == Status::Pass) {
+        (StatusCode::OK, Status::Pass)
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, Status::Fail)
+    };
+
+    (code, Json(Report { status, checks }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::future::ready;
+
+    async fn readiness(health: Health) -> (StatusCode, Report) {
+        let (code, Json(report)) = readyz(State(Arc::new(health))).await;
+        (code, report)
+    }
+
+    #[tokio::test]
+    async fn ready_when_every_check_passes() {
+        let health = Health::new()
+            .live("alive", || ready(Ok(())))
+            .ready("nats", || ready(Ok(())));
+
+        let (code, report) = readiness(health).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(report.status == Status::Pass);
+        assert_eq!(report.checks.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn unavailable_when_a_check_fails() {
+        let health = Health::new()
+            .ready("nats", || ready(Ok(())))
+            .ready("commands", || {
+                ready(Err("Command watcher is dead".to_string()))
+            });
+
+        let (code, report) = readiness(health).await;
+        assert_eq!(code, StatusCode::SERVICE_UNAVAILABLE);
+        assert!(report.status == Status::Fail);
+        assert!(report.checks["nats"].status == Status::Pass);
+        assert!(report.checks["commands"].status == Status::Fail);
+        assert_eq!(
+            report.checks["commands"].error.as_deref(),
+            Some("Command watcher is dead")
+        );
+    }
+
+    #[tokio::test]
+    async fn spawn_fails_on_taken_address() {
+        let taken = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+
+        assert!(Health::new().spawn(taken.local_addr().unwrap()).is_err());
+    }
+
+    #[tokio::test]
+    async fn liveness_skips_readiness() {
+        let health = Health::new().ready("shutdown", || ready(Err("Shutting down".to_string())));
+
+        let (code, Json(report)) = healthz(State(Arc::new(health))).await;
+        assert_eq!(code, StatusCode::OK);
+        assert!(report.checks.is_empty());
+    }
+}
@@ -84,7 +84,7 @@ impl Instrumentation {
 
                 // If the `--log-directive` is specified, don't set a default
                 if self.log_directives.is_empty() {
-                    EnvFilter::try_new(&format!(
+                    EnvFilter::try_new(format!(
                         "{}={}",
                         env::current_exe()?
                             .file_name().ok_or(eyre::eyre!("Failed to get file_name"))?
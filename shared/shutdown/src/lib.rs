@@ -0,0 +1,214 @@
+use std::{future::Future, time::Duration};
+
+use async_nats::{Message, Subscriber};
+use futures::{
+    future::BoxFuture,
+    stream::{self, BoxStream},
+    FutureExt, Stream, StreamExt, TryFutureExt,
+};
+use tokio::sync::watch;
+use tracing::{info, trace, warn};
+
+/// Request to shut down a service, made by SIGTERM or SIGINT
+#[derive(Clone)]
+pub struct Shutdown {
+    requested: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    /// Start waiting for a signal
+    pub fn listen() -> Self {
+        let (tx, rx) = watch::channel(false);
+
+        tokio::spawn(async move {
+            signal().await;
+            info!("Shutdown requested");
+            tx.send_replace(true);
+        });
+
+        Self { requested: rx }
+    }
+
+    /// A request made by sending `true` rather than by a signal, e.g. in tests
+    pub fn manual() -> (watch::Sender<bool>, Self) {
+        let (tx, rx) = watch::channel(false);
+        (tx, Self { requested: rx })
+    }
+
+    pub fn is_requested(&self) -> bool {
+        *self.requested.borrow()
+    }
+
+    /// Resolves once shutdown is requested
+    pub async fn requested(&self) {
+        let mut requested = self.requested.clone();
+        // The sender lives as long as no signal is received
+        let _ = requested.wait_for(|&requested| requested).await;
+    }
+
+    /// Resolves once the grace period has passed since shutdown was requested
+    pub async fn expired(&self, grace_period: Duration) {
+        self.requested().await;
+        tokio::time::sleep(grace_period).await;
+        warn!("Grace period of {:?} has expired", grace_period);
+    }
+
+    /// Run the work to its end, or give `None` if it's still running once the grace period
+    /// has expired since shutdown was requested
+    pub async fn within<F: Future>(&self, grace_period: Duration, work: F) -> Option<F::Output> {
+        tokio::select! {
+            output = work => Some(output),
+            _ = self.expired(grace_period) => None,
+        }
+    }
+}
+
+async fn signal() {
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("SIGTERM handler is installed")
+            .recv()
+            .await;
+    };
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = tokio::signal::ctrl_c() => trace!("Got SIGINT"),
+        _ = terminate => trace!("Got SIGTERM"),
+    }
+}
+
+/// A subscription which can be told to stop delivering messages
+trait Unsubscribe: Stream + Unpin + Send + 'static {
+    fn unsubscribe(&mut self) -> BoxFuture<'_, Result<(), String>>;
+}
+
+impl Unsubscribe for Subscriber {
+    fn unsubscribe(&mut self) -> BoxFuture<'_, Result<(), String>> {
+        Subscriber::unsubscribe(self)
+            .map_err(|e| e.to_string())
+            .boxed()
+    }
+}
+
+/// Messages of the subscription until shutdown is requested, then the ones already delivered
+///
+/// The subscription is unsubscribed once shutdown is requested, so that the server stops
+/// sending messages to it and the stream ends after the delivered ones.
+pub fn drain(subscriber: Subscriber, shutdown: Shutdown) -> BoxStream<'static, Message> {
+    drain_subscription(subscriber, shutdown)
+}
+
+fn drain_subscription<S>(subscriber: S, shutdown: Shutdown) -> BoxStream<'static, S::Item>
+where
+    S: Unsubscribe,
+    S::Item: Send,
+{
+    stream::unfold(
+        (subscriber, shutdown, false),
+        |(mut subscriber, shutdown, draining)| async move {
+            if !draining {
+                tokio::select! {
+                    msg = subscriber.next() => {
+                        return msg.map(|msg| (msg, (subscriber, shutdown, false)));
+                    }
+                    _ = shutdown.requested() => {}
+                }
+
+                info!("Drain the subscription");
+                if let Err(e) = subscriber.unsubscribe().await {
+                    warn!("Failed to unsubscribe: {}", e);
+                }
+            }
+
+            subscriber
+                .next()
+                .await
+                .map(|msg| (msg, (subscriber, shutdown, true)))
+        },
+    )
+    .boxed()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use super::*;
+    use tokio::{sync::mpsc, time::Instant};
+
+    /// Stops taking messages once unsubscribed, but keeps the ones already taken
+    struct Subscription(mpsc::UnboundedReceiver<u32>);
+
+    impl Stream for Subscription {
+        type Item = u32;
+
+        fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<u32>> {
+            self.0.poll_recv(cx)
+        }
+    }
+
+    impl Unsubscribe for Subscription {
+        fn unsubscribe(&mut self) -> BoxFuture<'_, Result<(), String>> {
+            self.0.close();
+            futures::future::ready(Ok(())).boxed()
+        }
+    }
+
+    #[tokio::test]
+    async fn drain_delivered_messages() {
+        let (requested, shutdown) = Shutdown::manual();
+        let (tx, rx) = mpsc::unbounded_channel();
+        let mut messages = drain_subscription(Subscription(rx), shutdown);
+
+        tx.send(1).unwrap();
+        assert_eq!(messages.next().await, Some(1));
+
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        requested.send_replace(true);
+
+        assert_eq!(messages.collect::<Vec<_>>().await, vec![2, 3]);
+        assert!(tx.send(4).is_err(), "Unsubscribed on shutdown");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expired_after_grace_period() {
+        let (requested, shutdown) = Shutdown::manual();
+        let grace_period = Duration::from_secs(10);
+
+        // Nothing expires until shutdown is requested
+        assert!(
+            tokio::time::timeout(Duration::from_secs(60), shutdown.expired(grace_period))
+                .await
+                .is_err()
+        );
+
+        requested.send_replace(true);
+        let start = Instant::now();
+        shutdown.expired(grace_period).await;
+        assert_eq!(start.elapsed(), grace_period);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn work_is_cut_after_grace_period() {
+        let (requested, shutdown) = Shutdown::manual();
+        let grace_period = Duration::from_secs(10);
+        let work = |secs| tokio::time::sleep(Duration::from_secs(secs));
+
+        // Work runs as long as it takes until shutdown is requested
+        assert!(shutdown.within(grace_period, work(60)).await.is_some());
+
+        requested.send_replace(true);
+        assert!(shutdown.within(grace_period, work(5)).await.is_some());
+
+        let start = Instant::now();
+        assert!(shutdown.within(grace_period, work(60)).await.is_none());
+        assert_eq!(start.elapsed(), grace_period);
+    }
+}
@@ -0,0 +1,115 @@
+use async_nats::jetstream::{
+    self,
+    context::Publish,
+    stream::{Config, RetentionPolicy},
+};
+use command::Command;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use time::OffsetDateTime;
+
+pub const STREAM: &str = "AUDIT";
+pub const SUBJECT_ISSUED: &str = "nats.audit.issued";
+pub const SUBJECT_APPLIED: &str = "nats.audit.applied";
+
+/// Append-only trail of command changes
+pub fn config() -> Config {
+    Config {
+        name: STREAM.to_string(),
+        retention: RetentionPolicy::Limits,
+        subjects: vec![SUBJECT_ISSUED.to_string(), SUBJECT_APPLIED.to_string()],
+        deny_delete: true,
+        deny_purge: true,
+        ..Default::default()
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+pub struct Record {
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+    #[serde(flatten)]
+    pub event: Event,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+#[cfg_attr(feature = "openapi", derive(utoipa::ToSchema))]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// A command change has been requested and stored in the `COMMANDS` stream
+    Issued {
+        client_ip: Option<String>,
+        identity: Option<String>,
+        #[cfg_attr(feature = "openapi", schema(value_type = Option<String>))]
+        old: Option<Command>,
+        #[cfg_attr(feature = "openapi", schema(value_type = String))]
+        new: Command,
+        /// Sequence of the command in the `COMMANDS` stream
+        sequence: u64,
+    },
+    /// A worker has switched to the command
+    Applied {
+        worker_id: String,
+        #[cfg_attr(feature = "openapi", schema(value_type = String))]
+        old: Command,
+        #[cfg_attr(feature = "openapi", schema(value_type = String))]
+        new: Command,
+        /// Sequence of the command in the `COMMANDS` stream
+        sequence: u64,
+    },
+}
+
+impl Event {
+    fn subject(&self) -> &'static str {
+        match self {
+            Event::Issued { .. } => SUBJECT_ISSUED,
+            Event::Applied { .. } => SUBJECT_APPLIED,
+        }
+    }
+
+    /// ID the stream tells repeated publishes of the event apart by
+    fn id(&self) -> String {
+        match self {
+            Event::Issued { sequence, .. } => format!("issued-{}", sequence),
+            Event::Applied {
+                worker_id,
+                sequence,
+                ..
+            } => format!("applied-{}-{}", worker_id, sequence),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+pub enum AuditError {
+    #[error("Failed to serialize an audit record")]
+    Serialize(#[from] serde_json::Error),
+    #[error("Failed to append an audit record")]
+    Publish(#[from] jetstream::context::PublishError),
+}
+
+/// Append the event to the trail and wait for the acknowledgement
+///
+/// The event is appended once however many times it's published within the duplicate
+/// window of the stream, so a failed publish may be retried safely.
+pub async fn publish(jetstream: &jetstream::Context, event: Event) -> Result<(), AuditError> {
+    let subject = event.subject();
+    let id = event.id();
+    let record = Record {
+        at: OffsetDateTime::now_utc(),
+        event,
+    };
+
+    jetstream
+        .send_publish(
+            subject,
+            Publish::build()
+                .payload(serde_json::to_vec(&record)?.into())
+                .message_id(id),
+        )
+        .await?
+        .await?;
+
+    Ok(())
+}
@@ -0,0 +1,17 @@
+use async_nats::jetstream::stream::{Config, RetentionPolicy};
+
+pub const STREAM: &str = "COMMANDS";
+pub const SUBJECT: &str = "nats.fnf";
+/// Number of the last commands kept, so that readers which fell behind catch up on them
+pub const HISTORY: i64 = 100;
+
+/// Keeps the command which is currently applied by the workers and the ones before it
+pub fn config() -> Config {
+    Config {
+        name: STREAM.to_string(),
+        retention: RetentionPolicy::Limits,
+        subjects: vec![SUBJECT.to_string()],
+        max_messages_per_subject: HISTORY,
+        ..Default::default()
+    }
+}
@@ -0,0 +1,595 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fmt, fs,
+    path::Path,
+    sync::Arc,
+};
+
+use crate::error::Result;
+use crate::tls::ClientCert;
+use axum::{
+    body::Body,
+    extract::State,
+    http::{header::AUTHORIZATION, HeaderMap, Request},
+    middleware::Next,
+    response::Response,
+};
+use color_eyre::eyre::{self, eyre, Context};
+use jsonwebtoken::{
+    decode, decode_header,
+    jwk::{AlgorithmParameters, EllipticCurve, Jwk, JwkSet},
+    Algorithm, DecodingKey, Validation,
+};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use thiserror::Error;
+use tracing::{debug, info, trace, warn};
+use utoipa::ToSchema;
+
+const API_KEY: &str = "x-api-key";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, ToSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum Scope {
+    /// Transform texts and read the commands
+    Transform,
+    /// Change the command and read the audit trail
+    Admin,
+}
+
+impl Scope {
+    pub const ALL: [Scope; 2] = [Scope::Transform, Scope::Admin];
+}
+
+impl fmt::Display for Scope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}",
+            match self {
+                Scope::Transform => "transform",
+                Scope::Admin => "admin",
+            }
+        )
+    }
+}
+
+impl TryFrom<&str> for Scope {
+    type Error = eyre::Report;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value {
+            "transform" => Ok(Scope::Transform),
+            "admin" => Ok(Scope::Admin),
+            _ => Err(eyre!("Unknown scope '{}'", value)),
+        }
+    }
+}
+
+/// Who made a request
+#[derive(Debug, Clone, Serialize)]
+pub struct Identity {
+    pub name: String,
+    pub scopes: HashSet<Scope>,
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+    #[error("No credentials provided")]
+    MissingCredentials,
+    #[error("Unknown API key")]
+    UnknownApiKey,
+    #[error("Unknown client certificate '{0}'")]
+    UnknownClientCert(String),
+    #[error("Invalid bearer token: {0}")]
+    InvalidToken(String),
+    #[error("Scope '{0}' is required")]
+    MissingScope(Scope),
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// Space-delimited scopes as of RFC 8693
+    #[serde(default)]
+    scope: String,
+}
+
+/// Sources of identities: static API keys, JWTs signed by keys of a local JWKS
+/// and client certificates verified by the TLS listener
+pub struct Auth {
+    /// Identities by SHA-256 hex digests of API keys
+    api_keys: HashMap<String, Identity>,
+    /// Identities by common names of client certificates
+    client_certs: HashMap<String, Identity>,
+    jwks: Option<JwkSet>,
+    issuer: Option<String>,
+    audience: Option<String>,
+}
+
+impl Auth {
+    /// Load API keys, client certificate mappings and JWKS
+    ///
+    /// An API key entry looks like `<sha256 hex of the key>:<identity>:<scope>[,<scope>...]`,
+    /// a file has an entry per line, empty lines and lines starting with `#` are skipped.
+    /// A client certificate entry looks like `<common name>:<identity>:<scope>[,<scope>...]`.
+    pub fn load(
+        api_keys: &[String],
+        api_keys_file: Option<&Path>,
+        client_certs: &[String],
+        jwks_file: Option<&Path>,
+        issuer: Option<String>,
+        audience: Option<String>,
+    ) -> Result<Self> {
+        let mut entries = api_keys.to_vec();
+        if let Some(path) = api_keys_file {
+            debug!("Read API keys from {}", path.display());
+            entries.extend(
+                fs::read_to_string(path)
+                    .wrap_err_with(|| format!("reading {}", path.display()))?
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .map(String::from),
+            );
+        }
+
+        let api_keys = entries
+            .iter()
+            .map(|entry| parse_api_key(entry))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let client_certs = client_certs
+            .iter()
+            .map(|entry| parse_entry(entry, "client certificate"))
+            .collect::<Result<HashMap<_, _>>>()?;
+
+        let jwks = jwks_file
+            .map(|path| {
+                debug!("Read JWKS from {}", path.display());
+                let jwks = serde_json::from_str::<JwkSet>(
+                    &fs::read_to_string(path)
+                        .wrap_err_with(|| format!("reading {}", path.display()))?,
+                )?;
+
+                Ok::<_, eyre::Report>(jwks)
+            })
+            .transpose()?;
+
+        info!(
+            "Loaded {} API keys, {} client certificates and {} JWKs",
+            api_keys.len(),
+            client_certs.len(),
+            jwks.as_ref().map_or(0, |jwks| jwks.keys.len())
+        );
+
+        Ok(Self {
+            api_keys,
+            client_certs,
+            jwks,
+            issuer,
+            audience,
+        })
+    }
+
+    /// Authentication is off while neither API keys, client certificates nor JWKS are configured
+    pub fn is_enabled(&self) -> bool {
+        !self.api_keys.is_empty() || !self.client_certs.is_empty() || self.jwks.is_some()
+    }
+
+    /// Find out who made a request by its `X-Api-Key` or `Authorization: Bearer` header,
+    /// or by the certificate of its client if it has neither
+    pub fn authenticate(
+        &self,
+        headers: &HeaderMap,
+        client_cert: Option<&ClientCert>,
+    ) -> Result<Identity, AuthError> {
+        if let Some(key) = headers.get(API_KEY) {
+            trace!("Authenticate by API key");
+
+            let digest = hex::encode(Sha256::digest(key.as_bytes()));
+            return self
+                .api_keys
+                .get(&digest)
+                .cloned()
+                .ok_or(AuthError::UnknownApiKey);
+        }
+
+        let token = headers
+            .get(AUTHORIZATION)
+            .and_then(|value| value.to_str().ok())
+            .and_then(|value| value.strip_prefix("Bearer "));
+
+        if let Some(token) = token {
+            trace!("Authenticate by bearer token");
+            return self
+                .verify(token)
+                .map_err(|e| AuthError::InvalidToken(e.to_string()));
+        }
+
+        let ClientCert(common_name) = client_cert.ok_or(AuthError::MissingCredentials)?;
+
+        trace!("Authenticate by client certificate");
+        self.client_certs
+            .get(common_name)
+            .cloned()
+            .ok_or_else(|| AuthError::UnknownClientCert(common_name.clone()))
+    }
+
+    fn verify(&self, token: &str) -> Result<Identity, eyre::Report> {
+        let jwks = self
+            .jwks
+            .as_ref()
+            .ok_or_else(|| eyre!("bearer tokens are not accepted"))?;
+
+        let header = decode_header(token)?;
+        let kid = header.kid.ok_or_else(|| eyre!("no key ID"))?;
+        let jwk = jwks
+            .find(&kid)
+            .ok_or_else(|| eyre!("unknown key ID '{}'", kid))?;
+
+        // The key decides the algorithm, a token can't pick another one
+        let algorithm = key_algorithm(jwk)?;
+        if header.alg != algorithm {
+            return Err(eyre!(
+                "algorithm {:?} doesn't match {:?} of key '{}'",
+                header.alg,
+                algorithm,
+                kid
+            ));
+        }
+
+        let mut validation = Validation::new(algorithm);
+        match &self.audience {
+            Some(audience) => validation.set_audience(&[audience]),
+            None => validation.validate_aud = false,
+        }
+        if let Some(issuer) = &self.issuer {
+            validation.set_issuer(&[issuer]);
+        }
+
+        let claims = decode::<Claims>(token, &DecodingKey::from_jwk(jwk)?, &validation)?.claims;
+
+        Ok(Identity {
+            name: claims.sub,
+            scopes: claims
+                .scope
+                .split_whitespace()
+                // Scopes of other services may share the token
+                .filter_map(|scope| Scope::try_from(scope).ok())
+                .collect(),
+        })
+    }
+}
+
+/// Algorithm of a JWK: its `alg` parameter or the default one of its key type
+fn key_algorithm(jwk: &Jwk) -> Result<Algorithm, eyre::Report> {
+    if let Some(algorithm) = &jwk.common.key_algorithm {
+        return algorithm
+            .to_string()
+            .parse()
+            .map_err(|_| eyre!("key algorithm {} is not for signing", algorithm));
+    }
+
+    match &jwk.algorithm {
+        AlgorithmParameters::RSA(_) => Ok(Algorithm::RS256),
+        AlgorithmParameters::EllipticCurve(params) => match params.curve {
+            EllipticCurve::P256 => Ok(Algorithm::ES256),
+            EllipticCurve::P384 => Ok(Algorithm::ES384),
+            ref curve => Err(eyre!("unsupported curve {:?}", curve)),
+        },
+        AlgorithmParameters::OctetKeyPair(_) => Ok(Algorithm::EdDSA),
+        AlgorithmParameters::OctetKey(_) => Ok(Algorithm::HS256),
+    }
+}
+
+fn parse_api_key(entry: &str) -> Result<(String, Identity)> {
+    let (digest, identity) = parse_entry(entry, "API key")?;
+
+    if digest.len() != 64 || !digest.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(eyre!("API key of '{}' is not a SHA-256 hex digest", identity.name).into());
+    }
+
+    Ok((digest.to_ascii_lowercase(), identity))
+}
+
+/// Split an entry `<key>:<identity>:<scope>[,<scope>...]`
+fn parse_entry(entry: &str, kind: &str) -> Result<(String, Identity)> {
+    let mut parts = entry.splitn(3, ':');
+    let (Some(key), Some(name), Some(scopes)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(eyre!("{} entry must look like <key>:<identity>:<scopes>", kind).into());
+    };
+
+    let identity = Identity {
+        name: name.to_string(),
+        scopes: scopes
+            .split(',')
+            .map(Scope::try_from)
+            .collect::<Result<_, _>>()?,
+    };
+
+    Ok((key.to_string(), identity))
+}
+
+/// Let the request through if its identity has the scope
+///
+/// The identity is put into the request extensions for handlers to pick it up.
+pub async fn require(
+    State((auth, scope)): State<(Arc<Auth>, Scope)>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response> {
+    if !auth.is_enabled() {
+        return Ok(next.run(request).await);
+    }
+
+    let identity = identify_request(&auth, &request)?;
+
+    if !identity.scopes.contains(&scope) {
+        warn!("'{}' lacks scope '{}'", identity.name, scope);
+        return Err(AuthError::MissingScope(scope).into());
+    }
+
+    request.extensions_mut().insert(identity);
+
+    Ok(next.run(request).await)
+}
+
+/// Let the request through if it's authenticated, whatever scopes its identity has
+///
+/// The identity is put into the request extensions as by [require].
+pub async fn identify(
+    State(auth): State<Arc<Auth>>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response> {
+    if auth.is_enabled() {
+        let identity = identify_request(&auth, &request)?;
+        request.extensions_mut().insert(identity);
+    }
+
+    Ok(next.run(request).await)
+}
+
+fn identify_request(auth: &Auth, request: &Request<Body>) -> Result<Identity, AuthError> {
+    let client_cert = request
+        .extensions()
+        .get::<Option<ClientCert>>()
+        .and_then(Option::as_ref);
+
+    let identity = auth
+        .authenticate(request.headers(), client_cert)
+        .map_err(|e| {
+            warn!("Authentication failed: {}", e);
+            e
+        })?;
+    debug!("Identity: {:?}", identity);
+
+    Ok(identity)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{http::StatusCode, routing::get, Router};
+    use base64::{engine::general_purpose::URL_SAFE_NO_PAD, Engine};
+    use jsonwebtoken::{encode, get_current_timestamp, EncodingKey, Header};
+    use ring::{
+        rand::SystemRandom,
+        signature::{EcdsaKeyPair, KeyPair, ECDSA_P256_SHA256_FIXED_SIGNING},
+    };
+    use serde_json::json;
+    use tower::ServiceExt;
+
+    fn auth(entries: &[&str]) -> Result<Auth> {
+        let entries = entries.iter().map(|e| e.to_string()).collect::<Vec<_>>();
+        Auth::load(&entries, None, &[], None, None, None)
+    }
+
+    fn with_api_key(key: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(API_KEY, key.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn api_key() {
+        let digest = hex::encode(Sha256::digest(b"secret"));
+        let auth = auth(&[&format!("{}:ci:transform,admin", digest)]).unwrap();
+
+        let identity = auth.authenticate(&with_api_key("secret"), None).unwrap();
+        assert_eq!(identity.name, "ci");
+        assert!(identity.scopes.contains(&Scope::Transform));
+        assert!(identity.scopes.contains(&Scope::Admin));
+
+        assert!(matches!(
+            auth.authenticate(&with_api_key("other"), None),
+            Err(AuthError::UnknownApiKey)
+        ));
+        assert!(matches!(
+            auth.authenticate(&HeaderMap::new(), None),
+            Err(AuthError::MissingCredentials)
+        ));
+    }
+
+    #[test]
+    fn bearer_without_jwks() {
+        let digest = hex::encode(Sha256::digest(b"secret"));
+        let auth = auth(&[&format!("{}:ci:transform", digest)]).unwrap();
+
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, "Bearer token".parse().unwrap());
+
+        assert!(matches!(
+            auth.authenticate(&headers, None),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[test]
+    fn client_cert() {
+        let entries = ["gateway:edge:transform".to_string()];
+        let auth = Auth::load(&[], None, &entries, None, None, None).unwrap();
+        assert!(auth.is_enabled());
+
+        let cert = ClientCert("gateway".to_string());
+        let identity = auth.authenticate(&HeaderMap::new(), Some(&cert)).unwrap();
+        assert_eq!(identity.name, "edge");
+        assert!(identity.scopes.contains(&Scope::Transform));
+
+        // Credentials in headers take precedence over the certificate
+        assert!(matches!(
+            auth.authenticate(&with_api_key("secret"), Some(&cert)),
+            Err(AuthError::UnknownApiKey)
+        ));
+
+        let other = ClientCert("other".to_string());
+        assert!(matches!(
+            auth.authenticate(&HeaderMap::new(), Some(&other)),
+            Err(AuthError::UnknownClientCert(_))
+        ));
+    }
+
+    /// Auth trusting a freshly generated P-256 key, and the key to sign tokens with
+    fn jwt_auth(alg: Option<&str>) -> (Auth, EncodingKey) {
+        let rng = SystemRandom::new();
+        let pkcs8 = EcdsaKeyPair::generate_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, &rng).unwrap();
+        let key_pair =
+            EcdsaKeyPair::from_pkcs8(&ECDSA_P256_SHA256_FIXED_SIGNING, pkcs8.as_ref(), &rng)
+                .unwrap();
+
+        // Uncompressed point: 0x04, x, y
+        let point = key_pair.public_key().as_ref();
+        let mut jwk = json!({
+            "kty": "EC",
+            "crv": "P-256",
+            "kid": "test",
+            "x": URL_SAFE_NO_PAD.encode(&point[1..33]),
+            "y": URL_SAFE_NO_PAD.encode(&point[33..]),
+        });
+        if let Some(alg) = alg {
+            jwk["alg"] = alg.into();
+        }
+
+        let auth = Auth {
+            api_keys: HashMap::new(),
+            client_certs: HashMap::new(),
+            jwks: Some(serde_json::from_value(json!({ "keys": [jwk] })).unwrap()),
+            issuer: Some("https://issuer.test".to_string()),
+            audience: Some("web-app".to_string()),
+        };
+
+        (auth, EncodingKey::from_ec_der(pkcs8.as_ref()))
+    }
+
+    fn token(key: &EncodingKey, claims: serde_json::Value) -> HeaderMap {
+        let mut header = Header::new(Algorithm::ES256);
+        header.kid = Some("test".to_string());
+        bearer(&encode(&header, &claims, key).unwrap())
+    }
+
+    fn bearer(token: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(AUTHORIZATION, format!("Bearer {}", token).parse().unwrap());
+        headers
+    }
+
+    fn claims(issuer: &str, audience: &str, exp: u64, scope: &str) -> serde_json::Value {
+        json!({
+            "sub": "client",
+            "iss": issuer,
+            "aud": audience,
+            "exp": exp,
+            "scope": scope,
+        })
+    }
+
+    fn in_an_hour() -> u64 {
+        get_current_timestamp() + 3600
+    }
+
+    #[test]
+    fn bearer_token() {
+        let (auth, key) = jwt_auth(None);
+        let claims = claims(
+            "https://issuer.test",
+            "web-app",
+            in_an_hour(),
+            "transform other:scope",
+        );
+
+        let identity = auth.authenticate(&token(&key, claims), None).unwrap();
+        assert_eq!(identity.name, "client");
+        assert_eq!(identity.scopes, HashSet::from([Scope::Transform]));
+    }
+
+    #[test]
+    fn bearer_token_rejected() {
+        let (auth, key) = jwt_auth(Some("ES256"));
+        let expired = get_current_timestamp() - 3600;
+
+        for claims in [
+            claims("https://other.test", "web-app", in_an_hour(), "transform"),
+            claims(
+                "https://issuer.test",
+                "other-app",
+                in_an_hour(),
+                "transform",
+            ),
+            claims("https://issuer.test", "web-app", expired, "transform"),
+        ] {
+            assert!(matches!(
+                auth.authenticate(&token(&key, claims), None),
+                Err(AuthError::InvalidToken(_))
+            ));
+        }
+    }
+
+    #[test]
+    fn bearer_token_algorithm_of_key() {
+        let (auth, key) = jwt_auth(Some("ES256"));
+        let claims = claims("https://issuer.test", "web-app", in_an_hour(), "transform");
+
+        // Same key and signature, but the header claims another algorithm
+        let signed = encode(&Header::new(Algorithm::ES256), &claims, &key).unwrap();
+        let (_, rest) = signed.split_once('.').unwrap();
+        let header = URL_SAFE_NO_PAD.encode(r#"{"alg":"ES384","kid":"test"}"#);
+
+        let result = auth.authenticate(&bearer(&format!("{}.{}", header, rest)), None);
+        assert!(matches!(result, Err(AuthError::InvalidToken(e)) if e.contains("doesn't match")));
+
+        // A key for encryption can't verify tokens
+        let (auth, key) = jwt_auth(Some("RSA-OAEP"));
+        assert!(matches!(
+            auth.authenticate(&token(&key, claims), None),
+            Err(AuthError::InvalidToken(_))
+        ));
+    }
+
+    #[tokio::test]
+    async fn bearer_token_without_scope() {
+        let (auth, key) = jwt_auth(None);
+        let app = Router::new().route("/", get(|| async {})).route_layer(
+            axum::middleware::from_fn_with_state((Arc::new(auth), Scope::Admin), require),
+        );
+
+        let claims = claims("https://issuer.test", "web-app", in_an_hour(), "transform");
+        let mut request = Request::get("/").body(Body::empty()).unwrap();
+        *request.headers_mut() = token(&key, claims);
+
+        let response = app.oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn malformed_entries() {
+        assert!(auth(&["no-colons"]).is_err());
+        assert!(auth(&["abc:ci:transform"]).is_err());
+        assert!(auth(&[&format!("{}:ci:root", "0".repeat(64))]).is_err());
+    }
+
+    #[test]
+    fn disabled_without_sources() {
+        assert!(!auth(&[]).unwrap().is_enabled());
+    }
+}
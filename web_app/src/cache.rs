@@ -0,0 +1,193 @@
+use std::{num::NonZeroUsize, sync::Mutex};
+
+use command::Command;
+use lru::LruCache;
+use metrics::counter;
+use sha2::{Digest, Sha256};
+use tracing::{debug, trace};
+
+/// Whether a transformation was answered from the cache
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CacheStatus {
+    Hit,
+    Miss,
+}
+
+impl CacheStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            CacheStatus::Hit => "HIT",
+            CacheStatus::Miss => "MISS",
+        }
+    }
+}
+
+/// An output of a worker kept for an input
+#[derive(Clone)]
+pub struct Cached {
+    pub output: String,
+    pub command: Command,
+    pub worker_id: String,
+}
+
+/// A command to override the active one by, if any, and the SHA-256 digest of an input
+type Key = (Option<&'static str>, [u8; 32]);
+
+/// Outputs of the latest transformations of the current command version
+///
+/// Transformations are pure functions of a command and an input, so their outputs hold
+/// until the `COMMANDS` stream advances, which empties the cache. Outputs of workers
+/// which have not applied the expected command yet are not kept.
+pub struct Cache {
+    inner: Mutex<Inner>,
+}
+
+struct Inner {
+    /// Sequence of the active command, 0 while there is none
+    version: u64,
+    active: Command,
+    entries: LruCache<Key, Cached>,
+}
+
+impl Cache {
+    pub fn new(capacity: NonZeroUsize, version: u64, active: Command) -> Self {
+        Self {
+            inner: Mutex::new(Inner {
+                version,
+                active,
+                entries: LruCache::new(capacity),
+            }),
+        }
+    }
+
+    /// Version of the command outputs are kept for, to tell whether it changes meanwhile
+    pub fn version(&self) -> u64 {
+        self.inner.lock().unwrap().version
+    }
+
+    pub fn get(&self, command: Option<Command>, input: &str) -> Option<Cached> {
+        let cached = self
+            .inner
+            .lock()
+            .unwrap()
+            .entries
+            .get(&key(command, input))
+            .cloned();
+
+        let status = if cached.is_some() {
+            CacheStatus::Hit
+        } else {
+            CacheStatus::Miss
+        };
+        trace!("Cache {}", status.as_str());
+        counter!("web_app_cache_requests_total", "result" => status.as_str()).increment(1);
+
+        cached
+    }
+
+    /// Keep the output unless the command has advanced past the version it was made by
+    /// or the worker applied another command than expected
+    pub fn put(&self, version: u64, command: Option<Command>, input: &str, cached: Cached) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.version != version {
+            trace!("Skip an output of version {}", version);
+        } else if cached.command != command.unwrap_or(inner.active) {
+            debug!(
+                "Skip an output of worker #{} made by {:?}",
+                cached.worker_id, cached.command
+            );
+        } else {
+            inner.entries.put(key(command, input), cached);
+        }
+    }
+
+    /// Drop the outputs of older commands
+    pub fn advance(&self, version: u64, active: Command) {
+        let mut inner = self.inner.lock().unwrap();
+
+        if inner.version != version {
+            debug!(
+                "Drop {} cached outputs of version {}",
+                inner.entries.len(),
+                inner.version
+            );
+            inner.version = version;
+            inner.active = active;
+            inner.entries.clear();
+            counter!("web_app_cache_invalidations_total").increment(1);
+        }
+    }
+}
+
+fn key(command: Option<Command>, input: &str) -> Key {
+    (
+        command.map(|command| command.code()),
+        Sha256::digest(input).into(),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cached(output: &str) -> Cached {
+        Cached {
+            output: output.to_string(),
+            command: Command::Reverse,
+            worker_id: "1".to_string(),
+        }
+    }
+
+    #[test]
+    fn hit_until_advanced() {
+        let cache = Cache::new(NonZeroUsize::new(2).unwrap(), 1, Command::Reverse);
+
+        assert!(cache.get(None, "abc").is_none());
+        cache.put(1, None, "abc", cached("cba"));
+        assert_eq!(cache.get(None, "abc").unwrap().output, "cba");
+
+        // An overriding command is a key of its own
+        assert!(cache.get(Some(Command::ToUpperCase), "abc").is_none());
+
+        cache.advance(2, Command::ToUpperCase);
+        assert!(cache.get(None, "abc").is_none());
+    }
+
+    #[test]
+    fn stale_outputs_are_not_kept() {
+        let cache = Cache::new(NonZeroUsize::new(2).unwrap(), 1, Command::Reverse);
+        let version = cache.version();
+
+        cache.advance(2, Command::Reverse);
+        cache.put(version, None, "abc", cached("cba"));
+
+        assert!(cache.get(None, "abc").is_none());
+    }
+
+    #[test]
+    fn outputs_of_lagging_workers_are_not_kept() {
+        let cache = Cache::new(NonZeroUsize::new(2).unwrap(), 1, Command::ToUpperCase);
+
+        cache.put(1, None, "abc", cached("cba"));
+        cache.put(1, Some(Command::Capitalize), "abc", cached("cba"));
+        assert!(cache.get(None, "abc").is_none());
+        assert!(cache.get(Some(Command::Capitalize), "abc").is_none());
+
+        cache.put(1, Some(Command::Reverse), "abc", cached("cba"));
+        assert!(cache.get(Some(Command::Reverse), "abc").is_some());
+    }
+
+    #[test]
+    fn least_recently_used_are_evicted() {
+        let cache = Cache::new(NonZeroUsize::new(2).unwrap(), 1, Command::Reverse);
+
+        cache.put(1, None, "a", cached("a"));
+        cache.put(1, None, "b", cached("b"));
+        cache.get(None, "a");
+        cache.put(1, None, "c", cached("c"));
+
+        assert!(cache.get(None, "a").is_some());
+        assert!(cache.get(None, "b").is_none());
+    }
+}
@@ -1,6 +1,6 @@
-use std::net::Ipv4Addr;
+use std::{net::Ipv4Addr, path::PathBuf};
 
-use clap::Parser;
+use clap::{ArgAction, Parser};
 
 #[derive(Parser)]
 pub struct Cli {
@@ -32,6 +32,192 @@ pub struct Cli {
     )]
     pub nats_port: u16,
 
+    /// Max number of items of a batch transformed concurrently
+    #[clap(
+        long,
+        env = "BATCH_CONCURRENCY",
+        default_value_t = 16,
+        value_parser = clap::value_parser!(u16).range(1..),
+    )]
+    pub batch_concurrency: u16,
+
+    /// Max number of requests in flight per WebSocket
+    #[clap(
+        long,
+        env = "WS_MAX_IN_FLIGHT",
+        default_value_t = 8,
+        value_parser = clap::value_parser!(u16).range(1..),
+    )]
+    pub ws_max_in_flight: u16,
+
+    /// Seconds between pings sent over a WebSocket
+    #[clap(
+        long,
+        env = "WS_PING_INTERVAL",
+        default_value_t = 30,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub ws_ping_interval: u64,
+
+    /// Milliseconds a transformation may take unless `X-Request-Timeout` says otherwise
+    #[clap(
+        long,
+        env = "REQUEST_TIMEOUT",
+        default_value_t = 5000,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub request_timeout: u64,
+
+    /// Max milliseconds a client may ask for by `X-Request-Timeout`
+    #[clap(
+        long,
+        env = "MAX_REQUEST_TIMEOUT",
+        default_value_t = 60000,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub max_request_timeout: u64,
+
+    /// Max number of transformation outputs kept in memory, the cache is off at 0
+    ///
+    /// The cache is emptied whenever the command changes.
+    #[clap(long, env = "CACHE_CAPACITY", default_value_t = 0)]
+    pub cache_capacity: usize,
+
+    /// Max bytes of a request body, after it's decompressed
+    #[clap(
+        long,
+        env = "MAX_BODY_SIZE",
+        default_value_t = 2 * 1024 * 1024,
+        value_parser = clap::value_parser!(u64).range(1..),
+    )]
+    pub max_body_size: u64,
+
+    /// Compress responses by gzip, br or zstd, whichever a client accepts
+    #[clap(long, env = "COMPRESSION", default_value_t = true, action = ArgAction::Set)]
+    pub compression: bool,
+
+    /// Origins browsers may make requests from, `*` for any, none by default
+    #[clap(long = "cors-origin", env = "CORS_ORIGINS", value_delimiter = ',')]
+    pub cors_origins: Vec<String>,
+
+    /// Methods of requests of other origins
+    #[clap(
+        long = "cors-method",
+        env = "CORS_METHODS",
+        value_delimiter = ',',
+        default_value = "GET,POST"
+    )]
+    pub cors_methods: Vec<String>,
+
+    /// Headers of requests of other origins
+    #[clap(
+        long = "cors-header",
+        env = "CORS_HEADERS",
+        value_delimiter = ',',
+        default_value = "authorization,content-type,x-api-key,x-request-id,x-request-timeout,idempotency-key,if-match,last-event-id"
+    )]
+    pub cors_headers: Vec<String>,
+
+    /// API key entries `<sha256 hex of the key>:<identity>:<scope>[,<scope>...]`
+    ///
+    /// Scopes are `transform` and `admin`. Authentication is off while neither API keys
+    /// nor JWKS are configured.
+    #[clap(long = "api-key", env = "API_KEYS", value_delimiter = ';')]
+    pub api_keys: Vec<String>,
+
+    /// File with an API key entry per line
+    #[clap(long, env = "API_KEYS_FILE")]
+    pub api_keys_file: Option<PathBuf>,
+
+    /// Client certificate entries `<common name>:<identity>:<scope>[,<scope>...]`
+    ///
+    /// Requests carrying neither an API key nor a bearer token are authenticated by
+    /// the subject of the certificate their client presented.
+    #[clap(long = "client-cert", env = "CLIENT_CERTS", value_delimiter = ';')]
+    pub client_certs: Vec<String>,
+
+    /// JWKS file with keys verifying bearer tokens
+    #[clap(long, env = "JWKS_FILE")]
+    pub jwks_file: Option<PathBuf>,
+
+    /// Issuer bearer tokens must have
+    #[clap(long, env = "JWT_ISSUER")]
+    pub jwt_issuer: Option<String>,
+
+    /// Audience bearer tokens must have
+    #[clap(long, env = "JWT_AUDIENCE")]
+    pub jwt_audience: Option<String>,
+
+    /// Requests per second a client may make to transform texts, on average
+    #[clap(
+        long,
+        env = "TRANSFORM_RATE",
+        default_value_t = 50,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub transform_rate: u32,
+
+    /// Requests a client may make to transform texts at once
+    #[clap(
+        long,
+        env = "TRANSFORM_BURST",
+        default_value_t = 100,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub transform_burst: u32,
+
+    /// Requests per second a client may make to administer commands, on average
+    #[clap(
+        long,
+        env = "ADMIN_RATE",
+        default_value_t = 1,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub admin_rate: u32,
+
+    /// Requests a client may make to administer commands at once
+    #[clap(
+        long,
+        env = "ADMIN_BURST",
+        default_value_t = 5,
+        value_parser = clap::value_parser!(u32).range(1..),
+    )]
+    pub admin_burst: u32,
+
+    /// PEM file with the certificate chain to serve over TLS, reloaded on change
+    #[clap(long, env = "TLS_CERT", requires = "tls_key")]
+    pub tls_cert: Option<PathBuf>,
+
+    /// PEM file with the private key of the certificate, reloaded on change
+    #[clap(long, env = "TLS_KEY", requires = "tls_cert")]
+    pub tls_key: Option<PathBuf>,
+
+    /// PEM file with CAs verifying certificates clients may present
+    #[clap(long, env = "TLS_CLIENT_CA", requires = "tls_cert")]
+    pub tls_client_ca: Option<PathBuf>,
+
+    /// Seconds given to in-flight work to finish once shutdown is requested
+    #[clap(long, env = "SHUTDOWN_GRACE_PERIOD", default_value_t = 30)]
+    pub shutdown_grace_period: u64,
+
     #[clap(flatten)]
     pub instrumentation: instrumentation::Instrumentation,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rates_and_bursts_are_positive() {
+        for arg in [
+            "--transform-rate",
+            "--transform-burst",
+            "--admin-rate",
+            "--admin-burst",
+        ] {
+            assert!(Cli::try_parse_from(["web_app", arg, "0"]).is_err());
+            assert!(Cli::try_parse_from(["web_app", arg, "1"]).is_ok());
+        }
+    }
+}
@@ -1,12 +1,22 @@
-use std::fmt;
+use std::{fmt, time::Duration};
 
 use axum::{
-    http::StatusCode,
+    http::{
+        header::{CONTENT_TYPE, RETRY_AFTER, WWW_AUTHENTICATE},
+        StatusCode,
+    },
     response::{IntoResponse, Response},
+    Json,
 };
 
+use crate::auth::AuthError;
+use crate::rate_limit::{RateLimited, RATE_LIMIT_LIMIT, RATE_LIMIT_REMAINING, RATE_LIMIT_RESET};
+use crate::request_id;
 use command::CommandError;
-use tracing::error;
+use serde::Serialize;
+use thiserror::Error;
+use tracing::{error, warn};
+use utoipa::ToSchema;
 
 pub type Result<T, E = Report> = color_eyre::Result<T, E>;
 
@@ -18,6 +28,12 @@ impl fmt::Debug for Report {
     }
 }
 
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.0, f)
+    }
+}
+
 impl<E> From<E> for Report
 where
     E: Into<color_eyre::Report>,
@@ -27,25 +43,424 @@ where
     }
 }
 
+/// A failure as a client sees it
+///
+/// Sources of failures either return it directly or attach it to their own errors
+/// by `wrap_err`, the rest is classified by [ApiError::from].
+#[derive(Debug, Clone, Error)]
+pub enum ApiError {
+    #[error("'{0}' is not a valid command")]
+    InvalidCommand(String),
+    #[error("{0}")]
+    InvalidHeader(&'static str),
+    #[error("{0}")]
+    InvalidUpload(String),
+    #[error("{0}")]
+    InvalidBody(String),
+    #[error("{0}")]
+    InvalidQuery(String),
+    #[error("{0}")]
+    UnsupportedMediaType(String),
+    #[error("{0}")]
+    UnprocessableBody(String),
+    #[error("{0}")]
+    Unauthorized(String),
+    #[error("{0}")]
+    Forbidden(String),
+    #[error("Too many requests, retry in {}s", retry_after.as_secs())]
+    RateLimited { limit: u32, retry_after: Duration },
+    #[error("The body exceeds {0} bytes")]
+    PayloadTooLarge(usize),
+    #[error("The active command is no longer the one of sequence {0}")]
+    PreconditionFailed(u64),
+    #[error("Malformed message from NATS: {0}")]
+    MalformedMessage(String),
+    #[error("No worker answered within {0:?}")]
+    TimedOut(Duration),
+    #[error("No processor is available")]
+    NoResponders,
+    #[error("No worker is available")]
+    NoWorkers,
+    #[error("The worker failed: {0}")]
+    WorkerFailed(String),
+    #[error("The command could not be stored")]
+    PublishFailed,
+    #[error("The command change could not be audited")]
+    AuditFailed,
+    #[error("NATS is unavailable")]
+    Nats,
+    #[error("Something went wrong")]
+    Internal,
+}
+
+impl ApiError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            ApiError::InvalidCommand(_)
+            | ApiError::InvalidHeader(_)
+            | ApiError::InvalidUpload(_)
+            | ApiError::InvalidBody(_)
+            | ApiError::InvalidQuery(_) => StatusCode::BAD_REQUEST,
+            ApiError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            ApiError::UnprocessableBody(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ApiError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            ApiError::Forbidden(_) => StatusCode::FORBIDDEN,
+            ApiError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            ApiError::PreconditionFailed(_) => StatusCode::PRECONDITION_FAILED,
+            ApiError::PayloadTooLarge(_) => StatusCode::PAYLOAD_TOO_LARGE,
+            ApiError::MalformedMessage(_) | ApiError::WorkerFailed(_) => StatusCode::BAD_GATEWAY,
+            ApiError::TimedOut(_) => StatusCode::GATEWAY_TIMEOUT,
+            ApiError::NoResponders
+            | ApiError::NoWorkers
+            | ApiError::PublishFailed
+            | ApiError::AuditFailed
+            | ApiError::Nats => StatusCode::SERVICE_UNAVAILABLE,
+            ApiError::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// Machine-readable code which stays the same whatever the message says
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidCommand(_) => "invalid_command",
+            ApiError::InvalidHeader(_) => "invalid_header",
+            ApiError::InvalidUpload(_) => "invalid_upload",
+            ApiError::InvalidBody(_) => "invalid_body",
+            ApiError::InvalidQuery(_) => "invalid_query",
+            ApiError::UnsupportedMediaType(_) => "unsupported_media_type",
+            ApiError::UnprocessableBody(_) => "unprocessable_body",
+            ApiError::Unauthorized(_) => "unauthorized",
+            ApiError::Forbidden(_) => "forbidden",
+            ApiError::RateLimited { .. } => "rate_limited",
+            ApiError::PreconditionFailed(_) => "precondition_failed",
+            ApiError::PayloadTooLarge(_) => "payload_too_large",
+            ApiError::MalformedMessage(_) => "malformed_message",
+            ApiError::TimedOut(_) => "timed_out",
+            ApiError::NoResponders => "no_responders",
+            ApiError::NoWorkers => "no_workers",
+            ApiError::WorkerFailed(_) => "worker_failed",
+            ApiError::PublishFailed => "publish_failed",
+            ApiError::AuditFailed => "audit_failed",
+            ApiError::Nats => "nats_unavailable",
+            ApiError::Internal => "internal",
+        }
+    }
+}
+
+impl From<&color_eyre::Report> for ApiError {
+    fn from(report: &color_eyre::Report) -> Self {
+        if let Some(e) = report.downcast_ref::<ApiError>() {
+            e.clone()
+        } else if let Some(CommandError::ParseFromString(value)) = report.downcast_ref() {
+            ApiError::InvalidCommand(value.clone())
+        } else if let Some(e) = report.downcast_ref::<RateLimited>() {
+            ApiError::RateLimited {
+                limit: e.limit,
+                retry_after: e.retry_after,
+            }
+        } else if let Some(e @ AuthError::MissingScope(_)) = report.downcast_ref() {
+            ApiError::Forbidden(e.to_string())
+        } else if let Some(e) = report.downcast_ref::<AuthError>() {
+            ApiError::Unauthorized(e.to_string())
+        } else {
+            ApiError::Internal
+        }
+    }
+}
+
+/// Body of an error response as of RFC 7807
+#[derive(Serialize, ToSchema)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    #[schema(example = "about:blank")]
+    kind: &'static str,
+    #[schema(example = "Gateway Timeout")]
+    title: &'static str,
+    #[schema(example = 504)]
+    status: u16,
+    #[schema(example = "No worker answered within 5s")]
+    detail: String,
+    /// Machine-readable code which stays the same whatever `detail` says
+    #[schema(example = "timed_out")]
+    code: &'static str,
+    /// ID of the request as of `X-Request-Id`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[schema(example = "2b7d7e2c-3e8e-4a43-9c49-2a4d1a6b8f0e")]
+    request_id: Option<String>,
+}
+
+/// The `code` and `detail` of a [Problem], for failures reported within a response, like
+/// the ones of items of a batch
+#[derive(Debug, Serialize, ToSchema)]
+pub struct ErrorDetail {
+    #[schema(example = "timed_out")]
+    pub code: &'static str,
+    #[schema(example = "No worker answered within 5s")]
+    pub detail: String,
+}
+
+impl From<ApiError> for ErrorDetail {
+    fn from(error: ApiError) -> Self {
+        Self {
+            code: error.code(),
+            detail: error.to_string(),
+        }
+    }
+}
+
+impl From<Report> for ErrorDetail {
+    fn from(report: Report) -> Self {
+        let error = ApiError::from(&report.0);
+        if error.status().is_server_error() {
+            error!("{:?}", report);
+        }
+
+        error.into()
+    }
+}
+
+impl IntoResponse for ApiError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        let problem = Problem {
+            kind: "about:blank",
+            title: status.canonical_reason().unwrap_or_default(),
+            status: status.as_u16(),
+            detail: self.to_string(),
+            code: self.code(),
+            request_id: request_id::current(),
+        };
+
+        let mut response = (
+            status,
+            [(CONTENT_TYPE, "application/problem+json")],
+            Json(problem),
+        )
+            .into_response();
+
+        let headers = response.headers_mut();
+        match self {
+            ApiError::RateLimited { limit, retry_after } => {
+                let retry_after = retry_after.as_secs();
+                headers.insert(RETRY_AFTER, retry_after.into());
+                headers.insert(RATE_LIMIT_LIMIT, limit.into());
+                headers.insert(RATE_LIMIT_REMAINING, 0.into());
+                headers.insert(RATE_LIMIT_RESET, retry_after.into());
+            }
+            ApiError::Unauthorized(_) => {
+                headers.insert(WWW_AUTHENTICATE, "Bearer".parse().unwrap());
+            }
+            _ => {}
+        }
+
+        response
+    }
+}
+
 impl IntoResponse for Report {
     fn into_response(self) -> Response {
         let report = self.0;
+        let error = ApiError::from(&report);
 
-        error!("{}", format!("{:?}", report));
-
-        if let Some(CommandError::ParseFromString(value)) =
-            report.downcast_ref::<CommandError>().map(|err| err)
-        {
-            (
-                StatusCode::BAD_REQUEST,
-                format!("'{}' is not a valid command", value),
-            )
+        if error.status().is_server_error() {
+            error!("{:?}", report);
         } else {
-            (
-                StatusCode::INTERNAL_SERVER_ERROR,
-                format!("Something went wrong"),
-            )
+            warn!("{}", report);
         }
-        .into_response()
+
+        error.into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::auth::Scope;
+    use async_nats::{jetstream::context::PublishErrorKind, RequestErrorKind};
+    use axum::http::HeaderMap;
+    use color_eyre::eyre::{eyre, WrapErr};
+    use serde_json::Value;
+
+    async fn problem(report: Report) -> (StatusCode, HeaderMap, Value) {
+        let (parts, body) = report.into_response().into_parts();
+
+        assert_eq!(parts.headers[CONTENT_TYPE], "application/problem+json");
+        let body = hyper::body::to_bytes(body).await.unwrap();
+        let body: Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["status"], parts.status.as_u16());
+
+        (parts.status, parts.headers, body)
+    }
+
+    async fn assert_problem(report: Report, status: StatusCode, code: &str) -> HeaderMap {
+        let (actual, headers, body) = problem(report).await;
+        assert_eq!(actual, status);
+        assert_eq!(body["code"], code);
+        headers
+    }
+
+    #[tokio::test]
+    async fn invalid_command() {
+        let report = Report::from(CommandError::ParseFromString("nop".into()));
+        assert_problem(report, StatusCode::BAD_REQUEST, "invalid_command").await;
+    }
+
+    #[tokio::test]
+    async fn invalid_header() {
+        let report = Report::from(ApiError::InvalidHeader("Bad header"));
+        assert_problem(report, StatusCode::BAD_REQUEST, "invalid_header").await;
+    }
+
+    #[tokio::test]
+    async fn unauthorized() {
+        let report = Report::from(AuthError::UnknownApiKey);
+        let headers = assert_problem(report, StatusCode::UNAUTHORIZED, "unauthorized").await;
+        assert_eq!(headers[WWW_AUTHENTICATE], "Bearer");
+    }
+
+    #[tokio::test]
+    async fn forbidden() {
+        let report = Report::from(AuthError::MissingScope(Scope::Admin));
+        assert_problem(report, StatusCode::FORBIDDEN, "forbidden").await;
+    }
+
+    #[tokio::test]
+    async fn rate_limited() {
+        let report = Report::from(RateLimited {
+            limit: 5,
+            retry_after: Duration::from_secs(3),
+        });
+        let headers = assert_problem(report, StatusCode::TOO_MANY_REQUESTS, "rate_limited").await;
+        assert_eq!(headers[RETRY_AFTER], "3");
+        assert_eq!(headers[RATE_LIMIT_LIMIT], "5");
+    }
+
+    #[tokio::test]
+    async fn precondition_failed() {
+        let report = Report::from(ApiError::PreconditionFailed(42));
+        assert_problem(
+            report,
+            StatusCode::PRECONDITION_FAILED,
+            "precondition_failed",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn payload_too_large() {
+        let report = Report::from(ApiError::PayloadTooLarge(1024));
+        assert_problem(report, StatusCode::PAYLOAD_TOO_LARGE, "payload_too_large").await;
+    }
+
+    #[tokio::test]
+    async fn malformed_message() {
+        let report = Report::from(ApiError::MalformedMessage("no worker ID found".into()));
+        assert_problem(report, StatusCode::BAD_GATEWAY, "malformed_message").await;
+    }
+
+    #[tokio::test]
+    async fn unclassified_parse_errors_are_internal() {
+        // Only replies of NATS are malformed messages, which are wrapped where they're read
+        let report = Report::from(serde_json::from_str::<Value>("{").unwrap_err());
+        assert_problem(report, StatusCode::INTERNAL_SERVER_ERROR, "internal").await;
+    }
+
+    #[tokio::test]
+    async fn invalid_body() {
+        let report = Report::from(ApiError::InvalidBody("Bad JSON".into()));
+        assert_problem(report, StatusCode::BAD_REQUEST, "invalid_body").await;
+
+        let report = Report::from(ApiError::UnprocessableBody("No input".into()));
+        assert_problem(
+            report,
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "unprocessable_body",
+        )
+        .await;
+    }
+
+    #[tokio::test]
+    async fn timed_out() {
+        let report = Report::from(ApiError::TimedOut(Duration::from_secs(5)));
+        assert_problem(report, StatusCode::GATEWAY_TIMEOUT, "timed_out").await;
+    }
+
+    #[tokio::test]
+    async fn no_responders() {
+        let report = Report::from(ApiError::NoResponders);
+        assert_problem(report, StatusCode::SERVICE_UNAVAILABLE, "no_responders").await;
+    }
+
+    #[tokio::test]
+    async fn no_workers() {
+        let report = Report::from(ApiError::NoWorkers);
+        assert_problem(report, StatusCode::SERVICE_UNAVAILABLE, "no_workers").await;
+    }
+
+    #[tokio::test]
+    async fn worker_failed() {
+        let report = Report::from(ApiError::WorkerFailed("broken pipe".into()));
+        let (status, _, body) = problem(report).await;
+        assert_eq!(status, StatusCode::BAD_GATEWAY);
+        assert_eq!(body["code"], "worker_failed");
+        assert_eq!(body["detail"], "The worker failed: broken pipe");
+    }
+
+    #[tokio::test]
+    async fn publish_failed() {
+        let report = Report::from(
+            Err::<(), _>(async_nats::jetstream::context::PublishError::from(
+                PublishErrorKind::TimedOut,
+            ))
+            .wrap_err(ApiError::PublishFailed)
+            .unwrap_err(),
+        );
+        assert_problem(report, StatusCode::SERVICE_UNAVAILABLE, "publish_failed").await;
+    }
+
+    #[tokio::test]
+    async fn audit_failed() {
+        let report = Report::from(eyre!("no ack").wrap_err(ApiError::AuditFailed));
+        assert_problem(report, StatusCode::SERVICE_UNAVAILABLE, "audit_failed").await;
+    }
+
+    #[tokio::test]
+    async fn nats_unavailable() {
+        let report = Report::from(
+            Err::<(), _>(async_nats::RequestError::from(RequestErrorKind::Other))
+                .wrap_err(ApiError::Nats)
+                .unwrap_err(),
+        );
+        assert_problem(report, StatusCode::SERVICE_UNAVAILABLE, "nats_unavailable").await;
+    }
+
+    #[tokio::test]
+    async fn request_id_in_body() {
+        let (_, _, body) = problem(Report::from(ApiError::NoResponders)).await;
+        assert!(body.get("request_id").is_none());
+
+        let report = Report::from(ApiError::NoResponders);
+        let (_, _, body) = request_id::within(Some("abc".to_string()), problem(report)).await;
+        assert_eq!(body["request_id"], "abc");
+    }
+
+    #[test]
+    fn error_details() {
+        let detail = ErrorDetail::from(Report::from(ApiError::NoWorkers));
+        assert_eq!(detail.code, "no_workers");
+        assert_eq!(detail.detail, "No worker is available");
+
+        let detail = ErrorDetail::from(Report::from(eyre!("secret").wrap_err("context")));
+        assert_eq!(detail.code, "internal");
+        assert_eq!(detail.detail, "Something went wrong");
+    }
+
+    #[tokio::test]
+    async fn internal_hides_details() {
+        let report = Report::from(eyre!("secret"));
+        let (status, _, body) = problem(report).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(body["code"], "internal");
+        assert_eq!(body["detail"], "Something went wrong");
     }
 }
@@ -0,0 +1,519 @@
+use std::{sync::Arc, time::Duration};
+
+use crate::error::ApiError;
+use crate::openapi::CommandCode;
+use crate::state::Config;
+use axum::{
+    async_trait,
+    body::Body,
+    extract::{FromRef, FromRequest, FromRequestParts, Multipart},
+    http::{
+        header::{CONTENT_TYPE, IF_MATCH},
+        request::Parts,
+        HeaderMap, Request, StatusCode,
+    },
+    response::{IntoResponse, Response},
+    Json,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use serde_json::{error::Category, Value};
+use tracing::debug;
+use utoipa::{
+    openapi::path::{Parameter, ParameterBuilder, ParameterIn},
+    IntoParams, PartialSchema, ToSchema,
+};
+
+const REQUEST_TIMEOUT: &str = "x-request-timeout";
+const IDEMPOTENCY_KEY: &str = "idempotency-key";
+/// Name of the multipart field carrying an uploaded file
+const FILE_FIELD: &str = "file";
+
+/// Input of a transformation
+///
+/// Taken either from a JSON body or, for any other content type, from a raw text body.
+#[derive(Deserialize, ToSchema)]
+pub struct TransformRequest {
+    pub input: String,
+    /// Code of a command to apply instead of the active one
+    #[schema(value_type = Option<CommandCode>)]
+    pub command: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequest<S, Body> for TransformRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        if has_content_type(req.headers(), "application/json") {
+            let Json(request) = Json::<Self>::from_request(req, state)
+                .await
+                .map_err(|e| body_rejected(e.status(), e.body_text()))?;
+
+            Ok(request)
+        } else {
+            let input = String::from_request(req, state)
+                .await
+                .map_err(|e| body_rejected(e.status(), e.body_text()))?;
+
+            Ok(Self {
+                input,
+                command: None,
+            })
+        }
+    }
+}
+
+/// Inputs of a batch transformation
+///
+/// Taken from a JSON array or, for `application/x-ndjson`, from a JSON value per line.
+/// Every item is either a string or an object like [TransformRequest]; an item which is
+/// neither is kept as an error, so that it fails alone.
+pub struct BatchRequest {
+    pub items: Vec<Result<TransformRequest, ApiError>>,
+    pub ndjson: bool,
+}
+
+#[async_trait]
+impl<S> FromRequest<S, Body> for BatchRequest
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        if has_content_type(req.headers(), "application/x-ndjson") {
+            let body = String::from_request(req, state)
+                .await
+                .map_err(|e| body_rejected(e.status(), e.body_text()))?;
+
+            let items = body
+                .lines()
+                .filter(|line| !line.trim().is_empty())
+                .map(|line| {
+                    serde_json::from_str(line)
+                        .map_err(json_rejected)
+                        .and_then(batch_item)
+                })
+                .collect();
+
+            Ok(Self {
+                items,
+                ndjson: true,
+            })
+        } else {
+            let Json(values) = Json::<Vec<Value>>::from_request(req, state)
+                .await
+                .map_err(|e| body_rejected(e.status(), e.body_text()))?;
+
+            let items = values.into_iter().map(batch_item).collect();
+
+            Ok(Self {
+                items,
+                ndjson: false,
+            })
+        }
+    }
+}
+
+/// A text file uploaded as the `file` field of a `multipart/form-data` body
+///
+/// Other fields are skipped. The file must be UTF-8, its size is limited like any body.
+pub struct FileUpload {
+    pub filename: Option<String>,
+    pub text: String,
+}
+
+#[async_trait]
+impl<S> FromRequest<S, Body> for FileUpload
+where
+    S: Send + Sync,
+{
+    type Rejection = Response;
+
+    async fn from_request(req: Request<Body>, state: &S) -> Result<Self, Self::Rejection> {
+        let mut multipart = Multipart::from_request(req, state)
+            .await
+            .map_err(|e| upload_failed(e.status(), e.body_text()))?;
+
+        while let Some(field) = multipart
+            .next_field()
+            .await
+            .map_err(|e| upload_failed(e.status(), e.body_text()))?
+        {
+            if field.name() != Some(FILE_FIELD) {
+                debug!("Skip the field {:?}", field.name());
+                continue;
+            }
+
+            let filename = field.file_name().map(String::from);
+            let bytes = field
+                .bytes()
+                .await
+                .map_err(|e| upload_failed(e.status(), e.body_text()))?;
+            let text = String::from_utf8(bytes.into()).map_err(|e| {
+                ApiError::InvalidUpload(format!("The file is not UTF-8: {}", e.utf8_error()))
+                    .into_response()
+            })?;
+            debug!("Uploaded {:?} of {} bytes", filename, text.len());
+
+            return Ok(Self { filename, text });
+        }
+
+        Err(ApiError::InvalidUpload(format!("No `{}` field found", FILE_FIELD)).into_response())
+    }
+}
+
+/// A body exceeding the limit is left for the body limit to report
+fn upload_failed(status: StatusCode, text: String) -> Response {
+    if status == StatusCode::PAYLOAD_TOO_LARGE {
+        (status, text).into_response()
+    } else {
+        ApiError::InvalidUpload(text).into_response()
+    }
+}
+
+/// A rejection of a body by axum as an [ApiError], except for a body exceeding the limit,
+/// which is left for the body limit to report
+fn body_rejected(status: StatusCode, text: String) -> Response {
+    match status {
+        StatusCode::PAYLOAD_TOO_LARGE => (status, text).into_response(),
+        StatusCode::UNSUPPORTED_MEDIA_TYPE => ApiError::UnsupportedMediaType(text).into_response(),
+        StatusCode::UNPROCESSABLE_ENTITY => ApiError::UnprocessableBody(text).into_response(),
+        _ => ApiError::InvalidBody(text).into_response(),
+    }
+}
+
+/// Query parameters like [axum::extract::Query], rejected as [ApiError::InvalidQuery]
+pub struct Query<T>(pub T);
+
+#[async_trait]
+impl<T, S> FromRequestParts<S> for Query<T>
+where
+    T: DeserializeOwned,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let axum::extract::Query(query) = axum::extract::Query::from_request_parts(parts, state)
+            .await
+            .map_err(|e| ApiError::InvalidQuery(e.body_text()))?;
+
+        Ok(Self(query))
+    }
+}
+
+/// Time a worker has to answer a transformation
+///
+/// Taken from the `X-Request-Timeout` header in milliseconds, capped by the configured max,
+/// or the configured default if there is no such header.
+#[derive(Clone, Copy)]
+pub struct Timeout(pub Duration);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Timeout
+where
+    Arc<Config>: FromRef<S>,
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let config = Arc::<Config>::from_ref(state);
+
+        let Some(value) = parts.headers.get(REQUEST_TIMEOUT) else {
+            return Ok(Self(config.request_timeout));
+        };
+
+        let millis = value
+            .to_str()
+            .ok()
+            .and_then(|value| value.parse::<u64>().ok())
+            .filter(|&millis| millis > 0)
+            .ok_or(ApiError::InvalidHeader(
+                "X-Request-Timeout must be a positive number of milliseconds",
+            ))?;
+
+        let timeout = Duration::from_millis(millis).min(config.max_request_timeout);
+        debug!("Request timeout: {:?}", timeout);
+
+        Ok(Self(timeout))
+    }
+}
+
+impl IntoParams for Timeout {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![ParameterBuilder::new()
+            .name("X-Request-Timeout")
+            .parameter_in(ParameterIn::Header)
+            .description(Some(
+                "Milliseconds a worker has to answer, capped by the configured max",
+            ))
+            .schema(Some(u64::schema()))
+            .build()]
+    }
+}
+
+/// Conditions a command change is published under
+///
+/// Taken from the `Idempotency-Key` header, which JetStream deduplicates changes by, and
+/// the `If-Match` header with the sequence of the command the change is meant to replace.
+#[derive(Debug, Default)]
+pub struct Preconditions {
+    pub idempotency_key: Option<String>,
+    pub expected_sequence: Option<u64>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Preconditions
+where
+    S: Send + Sync,
+{
+    type Rejection = ApiError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let idempotency_key = parts
+            .headers
+            .get(IDEMPOTENCY_KEY)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .filter(|key| !key.is_empty())
+                    .map(String::from)
+                    .ok_or(ApiError::InvalidHeader(
+                        "Idempotency-Key must be a non-empty ASCII string",
+                    ))
+            })
+            .transpose()?;
+
+        let expected_sequence = parts
+            .headers
+            .get(IF_MATCH)
+            .map(|value| {
+                value
+                    .to_str()
+                    .ok()
+                    .and_then(parse_sequence_tag)
+                    .ok_or(ApiError::InvalidHeader(
+                        "If-Match must be the sequence of a command as an entity tag",
+                    ))
+            })
+            .transpose()?;
+
+        let preconditions = Self {
+            idempotency_key,
+            expected_sequence,
+        };
+        debug!("Preconditions: {:?}", preconditions);
+
+        Ok(preconditions)
+    }
+}
+
+impl IntoParams for Preconditions {
+    fn into_params(_: impl Fn() -> Option<ParameterIn>) -> Vec<Parameter> {
+        vec![
+            ParameterBuilder::new()
+                .name("Idempotency-Key")
+                .parameter_in(ParameterIn::Header)
+                .description(Some(
+                    "Key of the change, a change repeated with the same key is dropped",
+                ))
+                .schema(Some(String::schema()))
+                .build(),
+            ParameterBuilder::new()
+                .name("If-Match")
+                .parameter_in(ParameterIn::Header)
+                .description(Some(
+                    "`ETag` of the active command the change is meant to replace",
+                ))
+                .schema(Some(String::schema()))
+                .build(),
+        ]
+    }
+}
+
+/// Sequence of a command out of a strong entity tag like `"42"`
+fn parse_sequence_tag(tag: &str) -> Option<u64> {
+    let tag = tag.trim();
+    let tag = tag
+        .strip_prefix('"')
+        .and_then(|tag| tag.strip_suffix('"'))
+        .unwrap_or(tag);
+
+    tag.parse().ok()
+}
+
+fn batch_item(value: Value) -> Result<TransformRequest, ApiError> {
+    match value {
+        Value::String(input) => Ok(TransformRequest {
+            input,
+            command: None,
+        }),
+        value => serde_json::from_value(value).map_err(json_rejected),
+    }
+}
+
+/// A JSON text of a client which can't be taken, like a body rejected by axum
+pub fn json_rejected(e: serde_json::Error) -> ApiError {
+    match e.classify() {
+        Category::Data => ApiError::UnprocessableBody(e.to_string()),
+        _ => ApiError::InvalidBody(e.to_string()),
+    }
+}
+
+fn has_content_type(headers: &HeaderMap, mime: &str) -> bool {
+    headers
+        .get(CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with(mime))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        routing::{get, post},
+        Router,
+    };
+    use tower::ServiceExt;
+
+    async fn upload(fields: &[(&str, Option<&str>, &str)]) -> Response {
+        let mut body = String::new();
+        for (name, filename, content) in fields {
+            body.push_str("--BOUNDARY\r\n");
+            body.push_str(&format!(
+                "Content-Disposition: form-data; name=\"{}\"",
+                name
+            ));
+            if let Some(filename) = filename {
+                body.push_str(&format!("; filename=\"{}\"", filename));
+            }
+            body.push_str(&format!("\r\n\r\n{}\r\n", content));
+        }
+        body.push_str("--BOUNDARY--\r\n");
+
+        let app =
+            Router::new().route(
+                "/",
+                post(|upload: FileUpload| async move {
+                    format!("{:?}: {}", upload.filename, upload.text)
+                }),
+            );
+        let request = Request::post("/")
+            .header(CONTENT_TYPE, "multipart/form-data; boundary=BOUNDARY")
+            .body(body.into())
+            .unwrap();
+
+        app.oneshot(request).await.unwrap()
+    }
+
+    async fn text(response: Response) -> String {
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+        String::from_utf8(body.to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn file_upload() {
+        let response = upload(&[
+            ("command", None, "rev"),
+            ("file", Some("export.csv"), "a,b\nc,d"),
+        ])
+        .await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(text(response).await, "Some(\"export.csv\"): a,b\nc,d");
+
+        let response = upload(&[("other", Some("export.csv"), "a,b")]).await;
+        assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+        assert!(text(response).await.contains("invalid_upload"));
+    }
+
+    /// Code of the problem a request is rejected with, `None` if it's accepted
+    async fn rejected(app: Router, request: Request<Body>) -> Option<(StatusCode, String)> {
+        let response = app.oneshot(request).await.unwrap();
+        if response.status().is_success() {
+            return None;
+        }
+
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let status = response.status();
+        let problem: Value = serde_json::from_str(&text(response).await).unwrap();
+        Some((status, problem["code"].as_str().unwrap().to_string()))
+    }
+
+    fn json(uri: &str, content_type: &str, body: &'static str) -> Request<Body> {
+        Request::post(uri)
+            .header(CONTENT_TYPE, content_type)
+            .body(body.into())
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn rejected_bodies_are_problems() {
+        let app = Router::new()
+            .route("/one", post(|_: TransformRequest| async {}))
+            .route("/many", post(|_: BatchRequest| async {}));
+        let rejected = |request| rejected(app.clone(), request);
+
+        assert!(
+            rejected(json("/one", "application/json", r#"{"input":"a"}"#))
+                .await
+                .is_none()
+        );
+        assert_eq!(
+            rejected(json("/one", "application/json", "{")).await,
+            Some((StatusCode::BAD_REQUEST, "invalid_body".into()))
+        );
+        assert_eq!(
+            rejected(json("/one", "application/json", r#"{"text":"a"}"#)).await,
+            Some((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                "unprocessable_body".into()
+            ))
+        );
+        assert_eq!(
+            rejected(json("/many", "text/plain", "[]")).await,
+            Some((
+                StatusCode::UNSUPPORTED_MEDIA_TYPE,
+                "unsupported_media_type".into()
+            ))
+        );
+        let request = Request::post("/one").body(vec![0xff].into()).unwrap();
+        assert_eq!(
+            rejected(request).await,
+            Some((StatusCode::BAD_REQUEST, "invalid_body".into()))
+        );
+    }
+
+    #[tokio::test]
+    async fn rejected_queries_are_problems() {
+        #[derive(Deserialize)]
+        struct Params {
+            limit: usize,
+        }
+        let app = Router::new().route(
+            "/",
+            get(|Query(params): Query<Params>| async move { params.limit.to_string() }),
+        );
+
+        let request = |uri| Request::get(uri).body(Body::empty()).unwrap();
+        assert!(rejected(app.clone(), request("/?limit=5")).await.is_none());
+        assert_eq!(
+            rejected(app, request("/?limit=many")).await,
+            Some((StatusCode::BAD_REQUEST, "invalid_query".into()))
+        );
+    }
+
+    #[test]
+    fn sequence_tags() {
+        assert_eq!(parse_sequence_tag("\"42\""), Some(42));
+        assert_eq!(parse_sequence_tag("42"), Some(42));
+        assert_eq!(parse_sequence_tag("W/\"42\""), None);
+        assert_eq!(parse_sequence_tag("*"), None);
+        assert_eq!(parse_sequence_tag("\"-1\""), None);
+    }
+}
@@ -0,0 +1,450 @@
+//! Transformation of whole text files uploaded as `multipart/form-data`
+//!
+//! A file is split into lines, or chunks of a given size, which are transformed
+//! concurrently and joined back in their order. A line which fails is kept as it was
+//! and reported by the summary headers.
+
+use std::{
+    convert::Infallible,
+    num::NonZeroUsize,
+    pin::Pin,
+    sync::Arc,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use crate::error::Result;
+use crate::extract::{FileUpload, Query, Timeout};
+use crate::openapi::{CommandCode, FileForm};
+use crate::rate_limit::Charge;
+use crate::request_id;
+use crate::state::{Config, Nats};
+use axum::{
+    async_trait,
+    body::{boxed, Bytes, HttpBody},
+    extract::{FromRequestParts, State},
+    http::{
+        header::{CONTENT_DISPOSITION, CONTENT_TYPE, TE, TRAILER},
+        request::Parts,
+        HeaderMap, HeaderName, HeaderValue, Version,
+    },
+    response::{IntoResponse, Response},
+    Extension,
+};
+use command::Command;
+use futures::{stream::BoxStream, StreamExt};
+use serde::Deserialize;
+use tracing::{debug, info, warn, Instrument, Span};
+use utoipa::IntoParams;
+
+const X_TRANSFORM_TOTAL: &str = "x-transform-total";
+const X_TRANSFORM_FAILED: &str = "x-transform-failed";
+const X_TRANSFORM_FAILED_AT: &str = "x-transform-failed-at";
+/// Numbers of failed pieces reported at most, to keep the header short
+const MAX_REPORTED_FAILURES: usize = 100;
+const DEFAULT_FILENAME: &str = "transformed.txt";
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct FileParams {
+    /// Code of a command to apply instead of the active one
+    #[param(value_type = Option<CommandCode>)]
+    command: Option<String>,
+    /// Split the file into chunks of at most that many bytes instead of lines
+    #[param(value_type = Option<usize>, minimum = 1)]
+    chunk_size: Option<NonZeroUsize>,
+}
+
+/// Transform a text file line by line or chunk by chunk
+///
+/// The file comes back as a download of the same name. Lines or chunks which fail are kept
+/// as they were; how many there were and which ones is told by the `X-Transform-*`
+/// headers. Clients accepting trailers over HTTP/2 (`TE: trailers`) get the file streamed
+/// as it's transformed and these headers as trailers, other clients once it's done. Every
+/// line or chunk takes a token of the rate limit.
+#[utoipa::path(
+    post,
+    path = "/v1/transform/file",
+    tag = "transform",
+    params(FileParams, Timeout),
+    request_body(content((FileForm = "multipart/form-data"))),
+    responses((
+        status = 200,
+        description = "Transformed file",
+        content((String = "text/plain")),
+        headers(
+            ("Content-Disposition" = String, description = "Attachment of the name of the uploaded file"),
+            ("X-Transform-Total" = usize, description = "Number of lines or chunks"),
+            ("X-Transform-Failed" = usize, description = "Number of lines or chunks which failed"),
+            ("X-Transform-Failed-At" = String, description = "Comma-separated numbers of the first 100 lines or chunks which failed, counting from 1"),
+        ),
+    )),
+)]
+pub async fn handler(
+    State(nats): State<Arc<Nats>>,
+    State(config): State<Arc<Config>>,
+    Timeout(timeout): Timeout,
+    Query(params): Query<FileParams>,
+    Trailers(trailers): Trailers,
+    charge: Option<Extension<Charge>>,
+    upload: FileUpload,
+) -> Result<Response> {
+    let command = params.command.map(String::try_into).transpose()?;
+
+    let pieces = split(&upload.text, params.chunk_size.map(NonZeroUsize::get));
+    info!("Transform a file of {} pieces", pieces.len());
+
+    if let Some(Extension(charge)) = charge {
+        charge.units(pieces.len())?;
+    }
+
+    let outcomes = transform_pieces(nats, pieces, command, timeout, config.batch_concurrency);
+
+    let mut response = if trailers {
+        debug!("Stream the file with the summary in trailers");
+        let mut response = Response::new(boxed(FileBody {
+            outcomes,
+            summary: Summary::default(),
+        }));
+        response.headers_mut().insert(
+            TRAILER,
+            HeaderValue::from_static(
+                "x-transform-total, x-transform-failed, x-transform-failed-at",
+            ),
+        );
+
+        response
+    } else {
+        let mut summary = Summary::default();
+        let mut file = String::with_capacity(upload.text.len());
+        for outcome in outcomes.collect::<Vec<_>>().await {
+            summary.add(&outcome);
+            file.push_str(&outcome.text);
+        }
+
+        let mut response = file.into_response();
+        response.headers_mut().extend(summary.headers());
+
+        response
+    };
+
+    let headers = response.headers_mut();
+    headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static("text/plain; charset=utf-8"),
+    );
+    headers.insert(
+        CONTENT_DISPOSITION,
+        attachment(upload.filename.as_deref()).try_into()?,
+    );
+
+    Ok(response)
+}
+
+/// A line or a chunk of a file and what follows it, which is not transformed
+#[derive(Debug, PartialEq)]
+struct Piece {
+    text: String,
+    separator: &'static str,
+}
+
+/// Split a text into lines with their line endings, or chunks of at most `chunk_size`
+/// bytes cut at character boundaries
+fn split(text: &str, chunk_size: Option<usize>) -> Vec<Piece> {
+    let Some(chunk_size) = chunk_size else {
+        return text
+            .split_inclusive('\n')
+            .map(|line| {
+                let (text, separator) = if let Some(text) = line.strip_suffix("\r\n") {
+                    (text, "\r\n")
+                } else if let Some(text) = line.strip_suffix('\n') {
+                    (text, "\n")
+                } else {
+                    (line, "")
+                };
+
+                Piece {
+                    text: text.to_string(),
+                    separator,
+                }
+            })
+            .collect();
+    };
+
+    let mut pieces = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut end = chunk_size.min(rest.len());
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        // A character larger than a chunk makes a chunk of its own
+        if end == 0 {
+            end = rest.chars().next().map_or(rest.len(), char::len_utf8);
+        }
+
+        let (chunk, tail) = rest.split_at(end);
+        pieces.push(Piece {
+            text: chunk.to_string(),
+            separator: "",
+        });
+        rest = tail;
+    }
+
+    pieces
+}
+
+/// A transformed piece, or the original one if it failed, along with its separator
+struct Outcome {
+    text: String,
+    failed: bool,
+}
+
+/// Outcomes of the pieces in their order, transforming some of them ahead at a time
+fn transform_pieces(
+    nats: Arc<Nats>,
+    pieces: Vec<Piece>,
+    command: Option<Command>,
+    timeout: Duration,
+    concurrency: usize,
+) -> BoxStream<'static, Outcome> {
+    // A streamed body is polled after the handler has returned
+    let request_id = request_id::current();
+    let span = Span::current();
+
+    futures::stream::iter(pieces.into_iter().enumerate())
+        .map(move |(index, piece)| {
+            let nats = Arc::clone(&nats);
+            let transform = async move {
+                match nats.transform(piece.text.clone(), command, timeout).await {
+                    Ok(transformed) => Outcome {
+                        text: transformed.output + piece.separator,
+                        failed: false,
+                    },
+                    Err(e) => {
+                        warn!("Piece #{} failed: {}", index + 1, e);
+                        Outcome {
+                            text: piece.text + piece.separator,
+                            failed: true,
+                        }
+                    }
+                }
+            };
+
+            request_id::within(request_id.clone(), transform.instrument(span.clone()))
+        })
+        .buffered(concurrency)
+        .boxed()
+}
+
+/// Numbers of the pieces which failed out of the pieces seen so far
+#[derive(Default)]
+struct Summary {
+    total: usize,
+    failed: Vec<usize>,
+}
+
+impl Summary {
+    fn add(&mut self, outcome: &Outcome) {
+        self.total += 1;
+        if outcome.failed {
+            self.failed.push(self.total);
+        }
+    }
+
+    fn headers(&self) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static(X_TRANSFORM_TOTAL),
+            self.total.into(),
+        );
+        headers.insert(
+            HeaderName::from_static(X_TRANSFORM_FAILED),
+            self.failed.len().into(),
+        );
+
+        if !self.failed.is_empty() {
+            let failed_at = self
+                .failed
+                .iter()
+                .take(MAX_REPORTED_FAILURES)
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(",");
+            headers.insert(
+                HeaderName::from_static(X_TRANSFORM_FAILED_AT),
+                HeaderValue::from_str(&failed_at).expect("digits and commas"),
+            );
+        }
+
+        headers
+    }
+}
+
+/// Body streaming the outcomes and then the summary of them as trailers
+struct FileBody {
+    outcomes: BoxStream<'static, Outcome>,
+    summary: Summary,
+}
+
+impl HttpBody for FileBody {
+    type Data = Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let body = &mut *self;
+
+        Poll::Ready(ready!(body.outcomes.poll_next_unpin(cx)).map(|outcome| {
+            body.summary.add(&outcome);
+            Ok(outcome.text.into())
+        }))
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(Some(self.summary.headers())))
+    }
+}
+
+/// Whether the client reads trailers
+pub struct Trailers(bool);
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Trailers
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        Ok(Self(accepts_trailers(parts.version, &parts.headers)))
+    }
+}
+
+/// Whether the client reads trailers, which HTTP/1.1 responses don't carry here
+fn accepts_trailers(version: Version, headers: &HeaderMap) -> bool {
+    version >= Version::HTTP_2
+        && headers
+            .get_all(TE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .any(|value| value.trim().eq_ignore_ascii_case("trailers"))
+}
+
+/// `Content-Disposition` of a download named after the uploaded file
+///
+/// Only the last component of the name is kept, and characters unsafe in a quoted
+/// string are replaced.
+fn attachment(filename: Option<&str>) -> String {
+    let filename = filename
+        .and_then(|name| name.rsplit(['/', '\\']).next())
+        .map(|name| {
+            name.chars()
+                .map(|c| {
+                    if c.is_ascii_alphanumeric() || matches!(c, '.' | '-' | '_' | ' ') {
+                        c
+                    } else {
+                        '_'
+                    }
+                })
+                .collect::<String>()
+        })
+        .filter(|name| !name.trim_matches(['.', ' ']).is_empty())
+        .unwrap_or_else(|| DEFAULT_FILENAME.to_string());
+
+    format!("attachment; filename=\"{}\"", filename)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn piece(text: &str, separator: &'static str) -> Piece {
+        Piece {
+            text: text.to_string(),
+            separator,
+        }
+    }
+
+    #[test]
+    fn split_by_lines() {
+        assert_eq!(
+            split("a,b\r\nc\n\nd", None),
+            [
+                piece("a,b", "\r\n"),
+                piece("c", "\n"),
+                piece("", "\n"),
+                piece("d", "")
+            ]
+        );
+        assert_eq!(split("a\n", None), [piece("a", "\n")]);
+        assert!(split("", None).is_empty());
+    }
+
+    #[test]
+    fn split_by_chunks() {
+        assert_eq!(
+            split("abcde", Some(2)),
+            [piece("ab", ""), piece("cd", ""), piece("e", "")]
+        );
+        // Characters are never cut
+        assert_eq!(
+            split("aéb", Some(2)),
+            [piece("a", ""), piece("é", ""), piece("b", "")]
+        );
+        assert_eq!(split("日本", Some(1)), [piece("日", ""), piece("本", "")]);
+    }
+
+    #[test]
+    fn summary_headers() {
+        let mut summary = Summary::default();
+        for failed in [false, true, false, true] {
+            summary.add(&Outcome {
+                text: String::new(),
+                failed,
+            });
+        }
+
+        let headers = summary.headers();
+        assert_eq!(headers[X_TRANSFORM_TOTAL], "4");
+        assert_eq!(headers[X_TRANSFORM_FAILED], "2");
+        assert_eq!(headers[X_TRANSFORM_FAILED_AT], "2,4");
+
+        assert!(!Summary::default()
+            .headers()
+            .contains_key(X_TRANSFORM_FAILED_AT));
+    }
+
+    #[test]
+    fn attachment_names() {
+        assert_eq!(
+            attachment(Some("export.csv")),
+            "attachment; filename=\"export.csv\""
+        );
+        assert_eq!(
+            attachment(Some("../logs/a\"b;c.log")),
+            "attachment; filename=\"a_b_c.log\""
+        );
+        assert_eq!(
+            attachment(Some("..")),
+            "attachment; filename=\"transformed.txt\""
+        );
+        assert_eq!(attachment(None), "attachment; filename=\"transformed.txt\"");
+    }
+
+    #[test]
+    fn trailers_over_http2_only() {
+        let mut headers = HeaderMap::new();
+        assert!(!accepts_trailers(Version::HTTP_2, &headers));
+
+        headers.insert(TE, HeaderValue::from_static("trailers"));
+        assert!(accepts_trailers(Version::HTTP_2, &headers));
+        assert!(!accepts_trailers(Version::HTTP_11, &headers));
+    }
+}
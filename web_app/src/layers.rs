@@ -0,0 +1,252 @@
+//! Layers shaping requests and responses of every route
+
+use crate::error::{ApiError, Report, Result};
+use axum::{
+    body::{Body, HttpBody},
+    extract::State,
+    http::{
+        header::{CONTENT_LENGTH, CONTENT_TYPE},
+        HeaderName, Method, Request, StatusCode,
+    },
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use color_eyre::eyre::eyre;
+use futures::stream;
+use tower::BoxError;
+use tower_http::{
+    compression::{
+        predicate::{And, DefaultPredicate, NotForContentType, Predicate},
+        CompressionLayer,
+    },
+    cors::{AllowOrigin, CorsLayer},
+    decompression::DecompressionBody,
+};
+use tracing::{debug, warn};
+
+/// Response headers scripts of other origins may read
+const EXPOSE_HEADERS: [&str; 10] = [
+    "etag",
+    "x-request-id",
+    "x-cache",
+    "x-processed-by",
+    "idempotent-replayed",
+    "ratelimit-limit",
+    "ratelimit-remaining",
+    "ratelimit-reset",
+    "retry-after",
+    "content-disposition",
+];
+
+/// CORS of the given origins, `*` standing for any origin
+///
+/// Requests of other origins are not let in while no origin is given.
+pub fn cors(origins: &[String], methods: &[String], headers: &[String]) -> Result<CorsLayer> {
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(
+            origins
+                .iter()
+                .map(|origin| origin.parse())
+                .collect::<Result<Vec<_>, _>>()?,
+        )
+    };
+
+    let methods = methods
+        .iter()
+        .map(|method| Method::from_bytes(method.to_ascii_uppercase().as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    let headers = headers
+        .iter()
+        .map(|header| HeaderName::from_bytes(header.as_bytes()))
+        .collect::<Result<Vec<_>, _>>()?;
+    debug!(
+        "CORS origins: {:?}, methods: {:?}, headers: {:?}",
+        origins, methods, headers
+    );
+
+    Ok(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods(methods)
+        .allow_headers(headers)
+        .expose_headers(EXPOSE_HEADERS.map(HeaderName::from_static)))
+}
+
+/// Compress responses by gzip, br or zstd, whichever a client accepts, if enabled
+///
+/// Server-sent events are left alone, as compressors buffer them instead of sending every
+/// event as it comes.
+pub fn compression(enabled: bool) -> CompressionLayer<And<DefaultPredicate, NotForContentType>> {
+    CompressionLayer::new()
+        .gzip(enabled)
+        .br(enabled)
+        .zstd(enabled)
+        .compress_when(
+            DefaultPredicate::new().and(NotForContentType::const_new("text/event-stream")),
+        )
+}
+
+/// Turn a decompressed body back into a plain one, which handlers and middlewares take
+pub fn decompressed(body: DecompressionBody<Body>) -> Body {
+    Body::wrap_stream(stream::unfold(Box::pin(body), |mut body| async move {
+        body.data().await.map(|chunk| (chunk, body))
+    }))
+}
+
+pub async fn decompression_failed(e: BoxError) -> Report {
+    eyre!(e).wrap_err("decompressing a request").into()
+}
+
+/// Reject bodies larger than the limit as a problem
+///
+/// A request with a larger `Content-Length` is rejected right away, a body which only
+/// turns out to be larger while it's read is rejected by its extractor; both get the same
+/// response.
+pub async fn limit_body(
+    State(limit): State<usize>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    let length = request
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+
+    if let Some(length) = length.filter(|&length| length > limit as u64) {
+        warn!("Body of {} bytes exceeds {} bytes", length, limit);
+        return ApiError::PayloadTooLarge(limit).into_response();
+    }
+
+    let response = next.run(request).await;
+
+    let is_problem = response
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|value| value == "application/problem+json");
+    if response.status() == StatusCode::PAYLOAD_TOO_LARGE && !is_problem {
+        warn!("Body exceeds {} bytes", limit);
+        return ApiError::PayloadTooLarge(limit).into_response();
+    }
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        error_handling::HandleErrorLayer,
+        extract::DefaultBodyLimit,
+        response::sse::{Event, Sse},
+        routing::{get, post},
+        Router,
+    };
+    use flate2::{write::GzEncoder, Compression};
+    use std::convert::Infallible;
+    use std::io::Write;
+    use tower::{ServiceBuilder, ServiceExt};
+    use tower_http::{
+        decompression::RequestDecompressionLayer, map_request_body::MapRequestBodyLayer,
+    };
+
+    const LIMIT: usize = 8;
+
+    /// Echo bodies the way `main.rs` limits and decompresses them
+    fn app() -> Router {
+        Router::new()
+            .route("/", post(|body: String| async move { body }))
+            .layer(axum::middleware::from_fn_with_state(LIMIT, limit_body))
+            .layer(DefaultBodyLimit::max(LIMIT))
+            .layer(MapRequestBodyLayer::new(decompressed))
+            .layer(
+                ServiceBuilder::new()
+                    .layer(HandleErrorLayer::new(decompression_failed))
+                    .layer(RequestDecompressionLayer::new()),
+            )
+    }
+
+    async fn send(request: Request<Body>) -> (StatusCode, String) {
+        let response = app().oneshot(request).await.unwrap();
+        let status = response.status();
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (status, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn within_limit() {
+        let (status, body) = send(Request::post("/").body("small".into()).unwrap()).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "small");
+    }
+
+    #[tokio::test]
+    async fn content_length_over_limit() {
+        let (status, body) = send(Request::post("/").body("far too large".into()).unwrap()).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body.contains("payload_too_large"));
+    }
+
+    #[tokio::test]
+    async fn streamed_body_over_limit() {
+        let chunks = stream::iter(["far ", "too ", "large"].map(Ok::<_, std::io::Error>));
+        let request = Request::post("/").body(Body::wrap_stream(chunks)).unwrap();
+
+        let (status, body) = send(request).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+        assert!(body.contains("payload_too_large"));
+    }
+
+    #[tokio::test]
+    async fn server_sent_events_are_not_compressed() {
+        let app = Router::new()
+            .route("/text", get(|| async { "a".repeat(1024) }))
+            .route(
+                "/events",
+                get(|| async {
+                    let event = Event::default().data("a".repeat(1024));
+                    Sse::new(stream::iter([Ok::<_, Infallible>(event)]))
+                }),
+            )
+            .layer(compression(true));
+        let content_encoding = |uri| {
+            let app = app.clone();
+            async move {
+                let request = Request::get(uri)
+                    .header("accept-encoding", "gzip")
+                    .body(Body::empty())
+                    .unwrap();
+                let response = app.oneshot(request).await.unwrap();
+                response.headers().get("content-encoding").cloned()
+            }
+        };
+
+        assert_eq!(content_encoding("/text").await.unwrap(), "gzip");
+        assert!(content_encoding("/events").await.is_none());
+    }
+
+    #[tokio::test]
+    async fn decompressed_body_is_limited() {
+        let gzip = |text: &str| {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(text.as_bytes()).unwrap();
+            encoder.finish().unwrap()
+        };
+        let request = |text| {
+            Request::post("/")
+                .header("content-encoding", "gzip")
+                .body(gzip(text).into())
+                .unwrap()
+        };
+
+        let (status, body) = send(request("small")).await;
+        assert_eq!(status, StatusCode::OK);
+        assert_eq!(body, "small");
+
+        // Fewer bytes than the limit once compressed
+        let (status, _) = send(request(&"a".repeat(64))).await;
+        assert_eq!(status, StatusCode::PAYLOAD_TOO_LARGE);
+    }
+}
@@ -1,19 +1,44 @@
+mod auth;
+mod cache;
 mod cli;
 mod error;
+mod extract;
+mod file;
+mod layers;
+mod openapi;
+mod rate_limit;
+mod request_id;
 mod route;
 mod state;
+mod tls;
 mod trace_layer;
+mod ui;
+mod ws;
 
-use std::{io::IsTerminal, net::SocketAddr, sync::Arc};
+use std::{io::IsTerminal, net::SocketAddr, num::NonZeroUsize, sync::Arc, time::Duration};
 
 use async_nats::ServerAddr;
-use axum::{routing::post, Router};
+use auth::{Auth, Scope};
+use axum::{
+    error_handling::HandleErrorLayer, extract::DefaultBodyLimit, http::HeaderName, middleware,
+    Router,
+};
 use clap::Parser;
 use cli::Cli;
 use error::Result;
-use state::Nats;
-use tower_http::trace::TraceLayer;
-use tracing::{trace, info};
+use health::{check, install_metrics};
+use rate_limit::RateLimiter;
+use shutdown::Shutdown;
+use state::{AppState, Config, Nats};
+use tls::Tls;
+use tower::ServiceBuilder;
+use tower_http::{
+    decompression::RequestDecompressionLayer,
+    map_request_body::MapRequestBodyLayer,
+    request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer},
+    trace::TraceLayer,
+};
+use tracing::{info, trace, warn};
 use url::Url;
 
 #[tokio::main]
@@ -31,31 +56,154 @@ async fn main() -> Result<()> {
     let cli = Cli::parse();
     cli.instrumentation.setup()?;
 
-    trace!("Setup app");
-    let app = Router::new()
-        .route("/request-reply/:message", post(route::request_reply))
-        .route("/fnf/:command", post(route::fire_and_forget))
-        .with_state(Arc::new(
-            Nats::new(ServerAddr::from_url(Url::parse(&format!(
+    let metrics = install_metrics()?;
+
+    trace!("Setup state");
+    let nats = Arc::new(
+        Nats::new(
+            ServerAddr::from_url(Url::parse(&format!(
                 "nats://{}:{}",
                 cli.nats_ip, cli.nats_port
-            ))?)?)
-            .await?,
+            ))?)?,
+            NonZeroUsize::new(cli.cache_capacity),
+        )
+        .await?,
+    );
+
+    let shutdown = Shutdown::listen();
+
+    trace!("Setup auth");
+    let auth = Arc::new(Auth::load(
+        &cli.api_keys,
+        cli.api_keys_file.as_deref(),
+        &cli.client_certs,
+        cli.jwks_file.as_deref(),
+        cli.jwt_issuer,
+        cli.jwt_audience,
+    )?);
+    if !auth.is_enabled() {
+        warn!("Authentication is off, configure API keys or JWKS to turn it on");
+    }
+
+    trace!("Setup app");
+    let max_body_size = usize::try_from(cli.max_body_size)?;
+    let transform = openapi::routes(openapi::transform())
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(
+                "transform",
+                cli.transform_rate.into(),
+                cli.transform_burst,
+            )),
+            rate_limit::limit,
         ))
+        .route_layer(middleware::from_fn_with_state(
+            (Arc::clone(&auth), Scope::Transform),
+            auth::require,
+        ));
+
+    let admin = openapi::routes(openapi::admin())
+        .route_layer(middleware::from_fn_with_state(
+            Arc::new(RateLimiter::new(
+                "admin",
+                cli.admin_rate.into(),
+                cli.admin_burst,
+            )),
+            rate_limit::limit,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            (Arc::clone(&auth), Scope::Admin),
+            auth::require,
+        ));
+
+    let identity = openapi::routes(openapi::identity()).route_layer(
+        middleware::from_fn_with_state(Arc::clone(&auth), auth::identify),
+    );
+
+    let app = Router::new()
+        .merge(transform)
+        .merge(admin)
+        .merge(identity)
+        .merge(ui::router())
+        .merge(
+            nats.health()
+                .ready("shutdown", check::shutdown(shutdown.clone()))
+                .metrics(metrics)
+                .router(),
+        )
+        .merge(openapi::router())
+        .with_state(AppState {
+            nats,
+            config: Arc::new(Config {
+                batch_concurrency: cli.batch_concurrency.into(),
+                ws_max_in_flight: cli.ws_max_in_flight.into(),
+                ws_ping_interval: Duration::from_secs(cli.ws_ping_interval),
+                request_timeout: Duration::from_millis(cli.request_timeout),
+                max_request_timeout: Duration::from_millis(cli.max_request_timeout),
+            }),
+            shutdown: shutdown.clone(),
+        })
+        .layer(middleware::from_fn_with_state(
+            max_body_size,
+            layers::limit_body,
+        ))
+        .layer(DefaultBodyLimit::max(max_body_size))
+        .layer(MapRequestBodyLayer::new(layers::decompressed))
+        .layer(
+            ServiceBuilder::new()
+                .layer(HandleErrorLayer::new(layers::decompression_failed))
+                .layer(RequestDecompressionLayer::new()),
+        )
+        .layer(layers::compression(cli.compression))
+        .layer(layers::cors(
+            &cli.cors_origins,
+            &cli.cors_methods,
+            &cli.cors_headers,
+        )?)
+        .layer(middleware::from_fn(request_id::scope))
         .layer(
             TraceLayer::new_for_http()
                 .make_span_with(trace_layer::trace_layer_make_span_with)
                 .on_request(trace_layer::trace_layer_on_request)
                 .on_response(trace_layer::trace_layer_on_response),
-        );
+        )
+        .layer(PropagateRequestIdLayer::new(HeaderName::from_static(
+            request_id::X_REQUEST_ID,
+        )))
+        .layer(SetRequestIdLayer::new(
+            HeaderName::from_static(request_id::X_REQUEST_ID),
+            MakeRequestUuid,
+        ));
 
     let bind = SocketAddr::new(cli.rest_ip.parse()?, cli.rest_port);
 
-    info!("Start listening on {}", &bind);
+    let grace_period = Duration::from_secs(cli.shutdown_grace_period);
+    let app = app.into_make_service_with_connect_info::<SocketAddr>();
+
+    match (cli.tls_cert, cli.tls_key) {
+        (Some(cert), Some(key)) => {
+            info!("Start listening on {} over TLS", &bind);
+
+            let tls = Tls {
+                cert,
+                key,
+                client_ca: cli.tls_client_ca,
+            };
+            tls.serve(bind, app, shutdown, grace_period).await?;
+        }
+        _ => {
+            info!("Start listening on {}", &bind);
+
+            let server = axum::Server::bind(&bind)
+                .serve(app)
+                .with_graceful_shutdown(shutdown.requested());
+
+            if let Some(res) = shutdown.within(grace_period, server).await {
+                res?;
+            }
+        }
+    }
 
-    axum::Server::bind(&bind)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await?;
+    info!("Stopped");
 
     Ok(())
 }
@@ -0,0 +1,338 @@
+//! OpenAPI document of the HTTP API served at `/openapi.json` and rendered at `/docs`
+
+use crate::error::Problem;
+use crate::extract::TransformRequest;
+use crate::state::AppState;
+use crate::{file, route, ws};
+use axum::{
+    handler::Handler,
+    http::{header::CONTENT_TYPE, Method},
+    response::{Html, IntoResponse},
+    routing::{get, on, MethodFilter, MethodRouter},
+    Router,
+};
+use command::Command;
+use serde::Deserialize;
+use tracing::trace;
+use utoipa::{
+    openapi::{
+        schema::{ObjectBuilder, Schema, Type},
+        security::{ApiKey, ApiKeyValue, HttpAuthScheme, HttpBuilder, SecurityScheme},
+        ContentBuilder, Ref, RefOr, ResponseBuilder, SecurityRequirement,
+    },
+    Modify, OpenApi, PartialSchema, ToSchema,
+};
+
+// The page is compiled into the binary, it needs neither network access nor inline scripts
+const DOCS: &str = include_str!("openapi/docs.html");
+const DOCS_JS: &str = include_str!("openapi/docs.js");
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "process-messages",
+        description = "Transform texts by commands of a fleet of workers",
+    ),
+    paths(
+        route::commands,
+        route::whoami,
+        route::request_reply,
+        route::transform,
+        route::transform_batch,
+        file::handler,
+        ws::handler,
+        route::active_command,
+        route::worker_commands,
+        route::command_events,
+        route::fire_and_forget,
+        route::audit,
+    ),
+    components(schemas(CommandCode, Problem, route::CommandsReset)),
+    modifiers(&Security, &Errors),
+    tags(
+        (name = "transform", description = "Requires the `transform` scope"),
+        (name = "admin", description = "Requires the `admin` scope"),
+        (name = "identity", description = "Requires any credentials"),
+    ),
+)]
+pub struct ApiDoc;
+
+/// Code of a command
+pub struct CommandCode;
+
+impl PartialSchema for CommandCode {
+    fn schema() -> RefOr<Schema> {
+        ObjectBuilder::new()
+            .schema_type(Type::String)
+            .enum_values(Some(Command::ALL.iter().map(Command::code)))
+            .into()
+    }
+}
+
+impl ToSchema for CommandCode {}
+
+/// An item of a batch: either an input or a whole transform request
+#[allow(dead_code)]
+#[derive(Deserialize, ToSchema)]
+#[serde(untagged)]
+pub enum BatchItem {
+    Input(String),
+    Request(TransformRequest),
+}
+
+/// A text file to transform, other fields are skipped
+#[allow(dead_code)]
+#[derive(ToSchema)]
+pub struct FileForm {
+    #[schema(value_type = String, format = Binary)]
+    file: Vec<u8>,
+}
+
+/// API keys and bearer tokens are both accepted
+struct Security;
+
+impl Modify for Security {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            "api_key",
+            SecurityScheme::ApiKey(ApiKey::Header(ApiKeyValue::new("X-Api-Key"))),
+        );
+        components.add_security_scheme(
+            "bearer",
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .build(),
+            ),
+        );
+
+        openapi.security = Some(vec![
+            SecurityRequirement::new("api_key", Vec::<String>::new()),
+            SecurityRequirement::new("bearer", Vec::<String>::new()),
+        ]);
+    }
+}
+
+/// Every operation may fail with a problem
+struct Errors;
+
+impl Modify for Errors {
+    fn modify(&self, openapi: &mut utoipa::openapi::OpenApi) {
+        openapi
+            .components
+            .get_or_insert_with(Default::default)
+            .responses
+            .insert(
+                "Problem".to_string(),
+                ResponseBuilder::new()
+                    .description("Failure described by its `code`")
+                    .content(
+                        "application/problem+json",
+                        ContentBuilder::new()
+                            .schema(Some(Ref::from_schema_name("Problem")))
+                            .build(),
+                    )
+                    .build()
+                    .into(),
+            );
+
+        for item in openapi.paths.paths.values_mut() {
+            for operation in [&mut item.get, &mut item.post].into_iter().flatten() {
+                operation.responses.responses.insert(
+                    "default".to_string(),
+                    Ref::from_response_name("Problem").into(),
+                );
+            }
+        }
+    }
+}
+
+/// An operation of the API, registered by the method and the path it's documented with
+pub struct Operation {
+    method: Method,
+    /// In the OpenAPI form, like `/fnf/{command}`
+    path: &'static str,
+    handler: MethodRouter<AppState>,
+}
+
+fn operation<H, T>(method: Method, path: &'static str, handler: H) -> Operation
+where
+    H: Handler<T, AppState>,
+    T: 'static,
+{
+    let filter = MethodFilter::try_from(method.clone()).expect("Method is filterable");
+
+    Operation {
+        method,
+        path,
+        handler: on(filter, handler),
+    }
+}
+
+/// Operations requiring the `transform` scope
+pub fn transform() -> Vec<Operation> {
+    vec![
+        operation(Method::GET, "/v1/commands", route::commands),
+        operation(
+            Method::POST,
+            "/request-reply/{message}",
+            route::request_reply,
+        ),
+        operation(Method::POST, "/v1/transform", route::transform),
+        operation(Method::POST, "/v1/transform/batch", route::transform_batch),
+        operation(Method::POST, "/v1/transform/file", file::handler),
+        operation(Method::GET, "/v1/ws", ws::handler),
+        operation(Method::GET, "/v1/command", route::active_command),
+        operation(Method::GET, "/v1/command/workers", route::worker_commands),
+        operation(Method::GET, "/v1/commands/events", route::command_events),
+    ]
+}
+
+/// Operations requiring the `admin` scope
+pub fn admin() -> Vec<Operation> {
+    vec![
+        operation(Method::POST, "/fnf/{command}", route::fire_and_forget),
+        operation(Method::GET, "/audit", route::audit),
+    ]
+}
+
+/// Operations requiring any credentials
+pub fn identity() -> Vec<Operation> {
+    vec![operation(Method::GET, "/v1/whoami", route::whoami)]
+}
+
+/// Router of operations, with their paths turned into the form of axum
+pub fn routes(operations: Vec<Operation>) -> Router<AppState> {
+    operations
+        .into_iter()
+        .fold(Router::new(), |router, operation| {
+            trace!("Route {} {}", operation.method, operation.path);
+            router.route(&axum_path(operation.path), operation.handler)
+        })
+}
+
+/// `/fnf/{command}` becomes `/fnf/:command`
+fn axum_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix('{') {
+            Some(param) => format!(":{}", param.trim_end_matches('}')),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Routes serving the document and a page rendering it
+pub fn router<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    let spec = ApiDoc::openapi()
+        .to_json()
+        .expect("OpenAPI document is serializable");
+
+    Router::new()
+        .route(
+            "/openapi.json",
+            get(|| async move { ([(CONTENT_TYPE, "application/json")], spec).into_response() }),
+        )
+        .route("/docs", get(|| async { Html(DOCS) }))
+        .route(
+            "/docs/docs.js",
+            get(|| async { ([(CONTENT_TYPE, "text/javascript")], DOCS_JS).into_response() }),
+        )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+    use utoipa::openapi::path::ParameterIn;
+
+    /// Methods and paths of the routes registered in `main.rs`
+    fn registered_routes() -> BTreeSet<(String, String)> {
+        [transform(), admin(), identity()]
+            .into_iter()
+            .flatten()
+            .map(|operation| {
+                (
+                    operation.method.as_str().to_lowercase(),
+                    operation.path.to_string(),
+                )
+            })
+            .collect()
+    }
+
+    fn documented_routes() -> BTreeSet<(String, String)> {
+        ApiDoc::openapi()
+            .paths
+            .paths
+            .into_iter()
+            .flat_map(|(path, item)| {
+                [("get", item.get), ("post", item.post)]
+                    .into_iter()
+                    .filter(|(_, operation)| operation.is_some())
+                    .map(move |(method, _)| (method.to_string(), path.clone()))
+            })
+            .collect()
+    }
+
+    #[test]
+    fn routes_match_spec() {
+        let registered = registered_routes();
+        assert!(!registered.is_empty());
+        assert_eq!(registered, documented_routes());
+    }
+
+    /// Path parameters are captured by the router, so each has to be a segment of the path
+    ///
+    /// Query parameters and bodies are read by the extractors of the handlers, which can't
+    /// be listed without calling the handlers, so they aren't compared.
+    #[test]
+    fn path_parameters_match_routes() {
+        let spec = ApiDoc::openapi();
+
+        for (method, path) in registered_routes() {
+            let item = &spec.paths.paths[&path];
+            let operation = match method.as_str() {
+                "get" => item.get.as_ref(),
+                "post" => item.post.as_ref(),
+                _ => None,
+            }
+            .unwrap();
+
+            let documented = operation
+                .parameters
+                .iter()
+                .flatten()
+                .filter(|parameter| parameter.parameter_in == ParameterIn::Path)
+                .map(|parameter| parameter.name.clone())
+                .collect::<BTreeSet<_>>();
+            let captured = axum_path(&path)
+                .split('/')
+                .filter_map(|segment| segment.strip_prefix(':'))
+                .map(String::from)
+                .collect::<BTreeSet<_>>();
+
+            assert_eq!(documented, captured, "{} {}", method, path);
+        }
+    }
+
+    #[test]
+    fn axum_paths() {
+        assert_eq!(axum_path("/fnf/{command}"), "/fnf/:command");
+        assert_eq!(axum_path("/v1/transform"), "/v1/transform");
+    }
+
+    #[test]
+    fn command_codes() {
+        let spec = serde_json::to_value(ApiDoc::openapi()).unwrap();
+        let codes = &spec["components"]["schemas"]["CommandCode"]["enum"];
+
+        for command in Command::ALL {
+            assert!(codes.as_array().unwrap().contains(&command.code().into()));
+        }
+    }
+}
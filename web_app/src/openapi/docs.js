@@ -0,0 +1,200 @@
+"use strict";
+
+const $ = (id) => document.getElementById(id);
+
+/// Create an element with its text or children
+function el(tag, content, className) {
+  const element = document.createElement(tag);
+  if (className) element.className = className;
+  if (Array.isArray(content)) element.append(...content.filter(Boolean));
+  else if (content !== undefined) element.textContent = content;
+  return element;
+}
+
+function credentials() {
+  return {
+    kind: sessionStorage.getItem("credential-kind") || "api-key",
+    value: sessionStorage.getItem("credential") || "",
+  };
+}
+
+function authHeaders() {
+  const { kind, value } = credentials();
+  if (!value) return {};
+  return kind === "bearer" ? { Authorization: `Bearer ${value}` } : { "X-Api-Key": value };
+}
+
+/// `#/components/responses/Problem` of the document
+function resolve(spec, item) {
+  if (!item || !item.$ref) return item;
+  return item.$ref
+    .replace(/^#\//, "")
+    .split("/")
+    .reduce((node, key) => node && node[key], spec);
+}
+
+/// Short description of a schema, like `string`, `[Item]` or `lc | uc`
+function typeOf(schema) {
+  if (!schema) return "";
+  if (schema.$ref) return schema.$ref.split("/").pop();
+  if (schema.enum) return schema.enum.join(" | ");
+  if (schema.oneOf || schema.anyOf) return (schema.oneOf || schema.anyOf).map(typeOf).join(" | ");
+  if (schema.type === "array") return `[${typeOf(schema.items)}]`;
+  const type = Array.isArray(schema.type) ? schema.type.join(" | ") : schema.type || "object";
+  return schema.format ? `${type} (${schema.format})` : type;
+}
+
+function parametersTable(parameters) {
+  const rows = parameters.map((parameter) =>
+    el("tr", [
+      el("td", parameter.name + (parameter.required ? " *" : "")),
+      el("td", parameter.in),
+      el("td", typeOf(parameter.schema)),
+      el("td", parameter.description || ""),
+    ]),
+  );
+  return el("table", [el("tr", ["Name", "In", "Type", "Description"].map((h) => el("th", h))), ...rows]);
+}
+
+function responsesTable(spec, responses) {
+  const rows = Object.entries(responses).map(([status, response]) => {
+    response = resolve(spec, response) || {};
+    const content = Object.entries(response.content || {})
+      .map(([type, media]) => `${type}${media.schema ? `: ${typeOf(media.schema)}` : ""}`)
+      .join(", ");
+    return el("tr", [el("td", status), el("td", response.description || ""), el("td", content)]);
+  });
+  return el("table", [el("tr", ["Status", "Description", "Content"].map((h) => el("th", h))), ...rows]);
+}
+
+/// Form sending the operation with the credentials and showing the response
+function tryIt(path, method, operation) {
+  const parameters = operation.parameters || [];
+  const inputs = new Map(
+    parameters.map((parameter) => [parameter, el("input")]),
+  );
+  const content = Object.keys((operation.requestBody || {}).content || {});
+  const body = content.includes("multipart/form-data")
+    ? Object.assign(el("input"), { type: "file" })
+    : content.length && Object.assign(el("textarea"), { placeholder: content[0] });
+  const output = el("pre", "");
+  const send = el("button", "Send");
+
+  send.addEventListener("click", async (event) => {
+    event.preventDefault();
+    let url = path;
+    const query = new URLSearchParams();
+    const headers = authHeaders();
+    for (const [parameter, input] of inputs) {
+      if (!input.value) continue;
+      if (parameter.in === "path") url = url.replace(`{${parameter.name}}`, encodeURIComponent(input.value));
+      else if (parameter.in === "query") query.append(parameter.name, input.value);
+      else if (parameter.in === "header") headers[parameter.name] = input.value;
+    }
+    if ([...query].length) url += `?${query}`;
+
+    const options = { method: method.toUpperCase(), headers };
+    if (body && body.type === "file") {
+      const form = new FormData();
+      if (body.files[0]) form.append("file", body.files[0]);
+      options.body = form;
+    } else if (body && body.value) {
+      headers["Content-Type"] = content[0];
+      options.body = body.value;
+    }
+
+    output.classList.remove("error");
+    output.textContent = "Sending…";
+    try {
+      const response = await fetch(url, options);
+      output.classList.toggle("error", !response.ok);
+      output.textContent = `${response.status} ${response.statusText}\n\n${await response.text()}`;
+    } catch (error) {
+      output.classList.add("error");
+      output.textContent = error.message;
+    }
+  });
+
+  return el("form", [
+    el("h3", "Try it"),
+    ...[...inputs].map(([parameter, input]) => el("div", [el("label", `${parameter.name} `), input], "row")),
+    body,
+    el("div", [send], "row"),
+    output,
+  ]);
+}
+
+function renderOperation(spec, path, method, operation) {
+  const summary = el("summary", [
+    el("span", method, `method ${method}`),
+    el("code", path),
+    operation.summary && el("span", ` — ${operation.summary}`, "muted"),
+  ]);
+
+  const requestBody = operation.requestBody;
+  return el("details", [
+    summary,
+    el("div", [
+      operation.description && el("p", operation.description),
+      (operation.parameters || []).length && el("h3", "Parameters"),
+      (operation.parameters || []).length && parametersTable(operation.parameters),
+      requestBody && el("h3", "Request body"),
+      requestBody &&
+        el(
+          "p",
+          Object.entries(requestBody.content || {})
+            .map(([type, media]) => `${type}: ${typeOf(media.schema)}`)
+            .join(", "),
+        ),
+      el("h3", "Responses"),
+      responsesTable(spec, operation.responses || {}),
+      tryIt(path, method, operation),
+    ]),
+  ]);
+}
+
+function render(spec) {
+  $("title").textContent = `${spec.info.title} ${spec.info.version || ""}`;
+  $("description").textContent = spec.info.description || "";
+
+  // Operations grouped by their first tag, in the order of the tags of the document
+  const groups = new Map((spec.tags || []).map((tag) => [tag.name, { tag, operations: [] }]));
+  for (const [path, item] of Object.entries(spec.paths)) {
+    for (const method of ["get", "post", "put", "patch", "delete"]) {
+      const operation = item[method];
+      if (!operation) continue;
+      const name = (operation.tags || ["default"])[0];
+      if (!groups.has(name)) groups.set(name, { tag: { name }, operations: [] });
+      groups.get(name).operations.push(renderOperation(spec, path, method, operation));
+    }
+  }
+
+  $("operations").replaceChildren(
+    ...[...groups.values()].flatMap(({ tag, operations }) => [
+      el("h2", tag.name),
+      tag.description && el("p", tag.description, "muted"),
+      ...operations,
+    ]).filter(Boolean),
+  );
+
+  $("schemas").replaceChildren(
+    ...Object.entries((spec.components || {}).schemas || {}).map(([name, schema]) =>
+      el("details", [el("summary", name), el("div", [el("pre", JSON.stringify(schema, null, 2))])]),
+    ),
+  );
+}
+
+$("credentials").addEventListener("submit", (event) => {
+  event.preventDefault();
+  sessionStorage.setItem("credential-kind", $("credential-kind").value);
+  sessionStorage.setItem("credential", $("credential").value);
+});
+$("credential-kind").value = credentials().kind;
+
+fetch("/openapi.json")
+  .then((response) => response.json())
+  .then(render)
+  .catch((error) => {
+    $("operations").textContent = `Can't load the document: ${error.message}`;
+    $("operations").className = "error";
+  });
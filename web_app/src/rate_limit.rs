@@ -0,0 +1,316 @@
+use std::{
+    collections::HashMap,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use crate::auth::Identity;
+use crate::error::Result;
+use axum::{
+    body::Body,
+    extract::{ConnectInfo, State},
+    http::{HeaderMap, HeaderValue, Request},
+    middleware::Next,
+    response::Response,
+};
+use thiserror::Error;
+use tracing::{debug, warn};
+
+pub const RATE_LIMIT_LIMIT: &str = "ratelimit-limit";
+pub const RATE_LIMIT_REMAINING: &str = "ratelimit-remaining";
+pub const RATE_LIMIT_RESET: &str = "ratelimit-reset";
+
+/// Buckets are swept of idle clients once there are that many of them
+const SWEEP_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Error)]
+#[error("Too many requests, retry in {}s", retry_after.as_secs())]
+pub struct RateLimited {
+    pub limit: u32,
+    pub retry_after: Duration,
+}
+
+/// Token buckets of clients which are refilled at `rate` tokens per second up to `burst`
+pub struct RateLimiter {
+    name: &'static str,
+    rate: f64,
+    burst: u32,
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// State of a bucket after a request took a token from it
+#[derive(Debug, PartialEq)]
+pub struct Quota {
+    pub remaining: u32,
+    /// Time until the bucket is full again
+    pub reset: Duration,
+}
+
+impl RateLimiter {
+    pub fn new(name: &'static str, rate: f64, burst: u32) -> Self {
+        Self {
+            name,
+            rate,
+            burst,
+            buckets: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Take a token from the bucket of the client
+    pub fn check(&self, key: &str, now: Instant) -> Result<Quota, RateLimited> {
+        self.take(key, 1, now)
+    }
+
+    /// Take that many tokens from the bucket of the client, or none if there are less
+    ///
+    /// More tokens than the burst are never there, so such a request always fails.
+    pub fn take(&self, key: &str, tokens: u32, now: Instant) -> Result<Quota, RateLimited> {
+        let tokens = tokens as f64;
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() >= SWEEP_THRESHOLD {
+            debug!("Sweep {} buckets", self.name);
+            buckets.retain(|_, bucket| self.refill(bucket, now) < self.burst as f64);
+        }
+
+        let bucket = buckets.entry(key.to_string()).or_insert(Bucket {
+            tokens: self.burst as f64,
+            updated: now,
+        });
+        bucket.tokens = self.refill(bucket, now);
+        bucket.updated = now;
+
+        if bucket.tokens < tokens {
+            return Err(RateLimited {
+                limit: self.burst,
+                retry_after: self.time_to(tokens - bucket.tokens),
+            });
+        }
+
+        bucket.tokens -= tokens;
+
+        Ok(self.quota(bucket))
+    }
+
+    /// State of the bucket of the client, without taking from it
+    fn peek(&self, key: &str, now: Instant) -> Quota {
+        let buckets = self.buckets.lock().unwrap();
+
+        match buckets.get(key) {
+            Some(bucket) => self.quota(&Bucket {
+                tokens: self.refill(bucket, now),
+                updated: now,
+            }),
+            None => self.quota(&Bucket {
+                tokens: self.burst as f64,
+                updated: now,
+            }),
+        }
+    }
+
+    fn quota(&self, bucket: &Bucket) -> Quota {
+        Quota {
+            remaining: bucket.tokens as u32,
+            reset: self.time_to(self.burst as f64 - bucket.tokens),
+        }
+    }
+
+    fn refill(&self, bucket: &Bucket, now: Instant) -> f64 {
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        (bucket.tokens + elapsed * self.rate).min(self.burst as f64)
+    }
+
+    /// Time to refill that many tokens, rounded up to seconds as headers carry seconds
+    fn time_to(&self, tokens: f64) -> Duration {
+        Duration::from_secs((tokens / self.rate).ceil() as u64)
+    }
+}
+
+/// The bucket of the client of a request, for handlers which fan the request out into many
+/// units of work, like items of a batch, to take a token for each of them
+#[derive(Clone)]
+pub struct Charge {
+    limiter: Arc<RateLimiter>,
+    key: String,
+}
+
+impl Charge {
+    /// Take a token for every unit of work but the first one, the request took it already
+    pub fn units(&self, count: usize) -> Result<(), RateLimited> {
+        let tokens = u32::try_from(count.saturating_sub(1)).unwrap_or(u32::MAX);
+        self.take(tokens)
+    }
+
+    /// Take that many tokens more
+    pub fn take(&self, tokens: u32) -> Result<(), RateLimited> {
+        if tokens == 0 {
+            return Ok(());
+        }
+
+        if let Err(e) = self.limiter.take(&self.key, tokens, Instant::now()) {
+            warn!(
+                "'{}' is rate limited by {} for {} tokens",
+                self.key, self.limiter.name, tokens
+            );
+            return Err(e);
+        }
+
+        Ok(())
+    }
+}
+
+/// Let the request through if its client has tokens left
+///
+/// The client is the identity set by authentication if any, or the IP address otherwise.
+/// Handlers take more tokens by the [Charge] the request is given. The headers tell what
+/// is left once the response is made.
+pub async fn limit(
+    State(limiter): State<Arc<RateLimiter>>,
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    mut request: Request<Body>,
+    next: Next<Body>,
+) -> Result<Response> {
+    let key = request
+        .extensions()
+        .get::<Identity>()
+        .map_or_else(|| addr.ip().to_string(), |identity| identity.name.clone());
+
+    if let Err(e) = limiter.check(&key, Instant::now()) {
+        warn!("'{}' is rate limited by {}", key, limiter.name);
+        return Err(e.into());
+    }
+
+    request.extensions_mut().insert(Charge {
+        limiter: Arc::clone(&limiter),
+        key: key.clone(),
+    });
+
+    let mut response = next.run(request).await;
+    let quota = limiter.peek(&key, Instant::now());
+    insert_headers(response.headers_mut(), limiter.burst, &quota);
+
+    Ok(response)
+}
+
+fn insert_headers(headers: &mut HeaderMap, limit: u32, quota: &Quota) {
+    headers.insert(RATE_LIMIT_LIMIT, HeaderValue::from(limit));
+    headers.insert(RATE_LIMIT_REMAINING, HeaderValue::from(quota.remaining));
+    headers.insert(RATE_LIMIT_RESET, HeaderValue::from(quota.reset.as_secs()));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::Report;
+    use crate::extract::BatchRequest;
+    use axum::{
+        http::{header::CONTENT_TYPE, StatusCode},
+        routing::post,
+        Extension, Router,
+    };
+    use tower::ServiceExt;
+
+    /// Charge batches the way `route::transform_batch` does
+    fn app(limiter: Arc<RateLimiter>) -> Router {
+        Router::new()
+            .route(
+                "/batch",
+                post(
+                    |Extension(charge): Extension<Charge>, batch: BatchRequest| async move {
+                        charge.units(batch.items.len())?;
+                        Ok::<_, Report>(())
+                    },
+                ),
+            )
+            .route_layer(axum::middleware::from_fn_with_state(limiter, limit))
+    }
+
+    async fn send_batch(app: Router, items: usize) -> Response {
+        let batch = serde_json::to_string(&vec!["abc"; items]).unwrap();
+        let mut request = Request::post("/batch")
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(batch))
+            .unwrap();
+        request
+            .extensions_mut()
+            .insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 1))));
+
+        app.oneshot(request).await.unwrap()
+    }
+
+    #[tokio::test]
+    async fn batches_take_a_token_per_item() {
+        let limiter = Arc::new(RateLimiter::new("test", 1.0, 10));
+
+        let response = send_batch(app(Arc::clone(&limiter)), 4).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[RATE_LIMIT_REMAINING], "6");
+
+        // A batch over the tokens left fails as a whole and takes none of them
+        let response = send_batch(app(Arc::clone(&limiter)), 8).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+
+        let response = send_batch(app(limiter), 5).await;
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[RATE_LIMIT_REMAINING], "0");
+    }
+
+    #[tokio::test]
+    async fn batches_over_burst_are_rejected() {
+        let limiter = Arc::new(RateLimiter::new("test", 1.0, 10));
+
+        let response = send_batch(app(limiter), 11).await;
+        assert_eq!(response.status(), StatusCode::TOO_MANY_REQUESTS);
+    }
+
+    #[test]
+    fn burst_then_refill() {
+        let limiter = RateLimiter::new("test", 1.0, 2);
+        let now = Instant::now();
+
+        assert_eq!(limiter.check("a", now).unwrap().remaining, 1);
+        assert_eq!(limiter.check("a", now).unwrap().remaining, 0);
+
+        let limited = limiter.check("a", now).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_secs(1));
+
+        // Other clients have buckets of their own
+        assert!(limiter.check("b", now).is_ok());
+
+        assert!(limiter.check("a", now + Duration::from_secs(1)).is_ok());
+        assert!(limiter.check("a", now + Duration::from_secs(1)).is_err());
+    }
+
+    #[test]
+    fn take_all_or_nothing() {
+        let limiter = RateLimiter::new("test", 1.0, 5);
+        let now = Instant::now();
+
+        assert_eq!(limiter.take("a", 3, now).unwrap().remaining, 2);
+
+        let limited = limiter.take("a", 3, now).unwrap_err();
+        assert_eq!(limited.retry_after, Duration::from_secs(1));
+        assert_eq!(limiter.peek("a", now).remaining, 2);
+
+        // More than the burst never fits
+        assert!(limiter.take("b", 6, now + Duration::from_secs(60)).is_err());
+    }
+
+    #[test]
+    fn refill_stops_at_burst() {
+        let limiter = RateLimiter::new("test", 10.0, 3);
+        let now = Instant::now();
+
+        limiter.check("a", now).unwrap();
+
+        let quota = limiter.check("a", now + Duration::from_secs(60)).unwrap();
+        assert_eq!(quota.remaining, 2);
+        assert_eq!(quota.reset, Duration::from_secs(1));
+    }
+}
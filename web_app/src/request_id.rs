@@ -0,0 +1,109 @@
+//! ID of the request being served, carried along to workers and back to the client
+//!
+//! The ID is taken from the `X-Request-Id` header or made up by `SetRequestIdLayer` and is
+//! available to the code serving the request by [current].
+
+use std::future::Future;
+
+use axum::{body::Body, http::Request, middleware::Next, response::Response};
+use tower_http::request_id::RequestId;
+use tracing::trace;
+
+pub const X_REQUEST_ID: &str = "x-request-id";
+
+tokio::task_local! {
+    static REQUEST_ID: String;
+}
+
+/// ID of the request being served, if any
+pub fn current() -> Option<String> {
+    REQUEST_ID.try_with(Clone::clone).ok()
+}
+
+/// Serve the request with its ID available by [current]
+pub async fn scope(request: Request<Body>, next: Next<Body>) -> Response {
+    match id(&request) {
+        Some(id) => {
+            trace!("Request ID: {}", id);
+            REQUEST_ID.scope(id, next.run(request)).await
+        }
+        None => next.run(request).await,
+    }
+}
+
+/// Run the future with the ID available by [current], e.g. in a spawned task
+pub async fn within<F: Future>(id: Option<String>, future: F) -> F::Output {
+    match id {
+        Some(id) => REQUEST_ID.scope(id, future).await,
+        None => future.await,
+    }
+}
+
+/// ID set to the request by `SetRequestIdLayer`
+pub fn id<B>(request: &Request<B>) -> Option<String> {
+    request
+        .extensions()
+        .get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .map(String::from)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::{
+        http::{HeaderName, StatusCode},
+        middleware,
+        routing::get,
+        Router,
+    };
+    use tower::ServiceExt;
+    use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, SetRequestIdLayer};
+
+    /// Answer with the current ID the way `main.rs` sets and echoes it
+    fn app() -> Router {
+        let header = HeaderName::from_static(X_REQUEST_ID);
+
+        Router::new()
+            .route("/", get(|| async { current().unwrap_or_default() }))
+            .layer(middleware::from_fn(scope))
+            .layer(PropagateRequestIdLayer::new(header.clone()))
+            .layer(SetRequestIdLayer::new(header, MakeRequestUuid))
+    }
+
+    async fn send(request: Request<Body>) -> (Option<String>, String) {
+        let response = app().oneshot(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+
+        let header = response
+            .headers()
+            .get(X_REQUEST_ID)
+            .map(|value| value.to_str().unwrap().to_string());
+        let body = hyper::body::to_bytes(response.into_body()).await.unwrap();
+
+        (header, String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn given_id_is_kept() {
+        let request = Request::get("/")
+            .header(X_REQUEST_ID, "abc")
+            .body(Body::empty())
+            .unwrap();
+
+        assert_eq!(send(request).await, (Some("abc".into()), "abc".into()));
+    }
+
+    #[tokio::test]
+    async fn missing_id_is_made_up() {
+        let (header, current) = send(Request::get("/").body(Body::empty()).unwrap()).await;
+
+        assert!(!current.is_empty());
+        assert_eq!(header, Some(current));
+    }
+
+    #[test]
+    fn no_id_out_of_scope() {
+        assert_eq!(current(), None);
+    }
+}
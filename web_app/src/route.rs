@@ -1,22 +1,641 @@
-use std::sync::Arc;
+use std::{
+    future::Future,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
-use crate::error::Result;
-use crate::state::Nats;
+use crate::auth::{Identity, Scope};
+use crate::cache::CacheStatus;
+use crate::error::{ApiError, ErrorDetail, Result};
+use crate::extract::{BatchRequest, Preconditions, Query, Timeout, TransformRequest};
+use crate::openapi::{BatchItem, CommandCode};
+use crate::rate_limit::Charge;
+use crate::state::{CommandEvent, Config, Issuer, Nats, WorkerCommand};
 use axum::{
-    extract::{Path, State},
-    response::IntoResponse,
+    body::StreamBody,
+    extract::{ConnectInfo, Path, State},
+    http::{
+        header::{CONTENT_TYPE, ETAG},
+        HeaderMap, HeaderName,
+    },
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Json,
 };
+use command::Command;
+use futures::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use shutdown::Shutdown;
+use streams::audit::Record;
+use time::OffsetDateTime;
+use tracing::{info, warn};
+use utoipa::{IntoParams, ToSchema};
+
+const AUDIT_MAX_LIMIT: usize = 1000;
+const LAST_EVENT_ID: &str = "last-event-id";
+const IDEMPOTENT_REPLAYED: &str = "idempotent-replayed";
+const X_CACHE: &str = "x-cache";
+const X_PROCESSED_BY: &str = "x-processed-by";
+
+/// A command of the registry
+#[derive(Serialize, ToSchema)]
+pub struct CommandInfo {
+    #[schema(value_type = CommandCode)]
+    code: &'static str,
+    description: &'static str,
+}
+
+/// Commands workers know
+#[utoipa::path(
+    get,
+    path = "/v1/commands",
+    tag = "transform",
+    responses((status = 200, description = "Registry of commands", body = Vec<CommandInfo>)),
+)]
+pub async fn commands() -> Json<Vec<CommandInfo>> {
+    Json(
+        Command::ALL
+            .iter()
+            .map(|command| CommandInfo {
+                code: command.code(),
+                description: command.description(),
+            })
+            .collect(),
+    )
+}
 
+#[derive(Serialize, ToSchema)]
+pub struct WhoAmI {
+    /// Name of the identity, none while authentication is off
+    identity: Option<String>,
+    /// Scopes of the identity, all of them while authentication is off
+    scopes: Vec<Scope>,
+}
+
+/// Who the credentials of the request belong to and what they let do
+#[utoipa::path(
+    get,
+    path = "/v1/whoami",
+    tag = "identity",
+    responses((status = 200, description = "Identity of the caller", body = WhoAmI)),
+)]
+pub async fn whoami(identity: Option<Extension<Identity>>) -> Json<WhoAmI> {
+    Json(match identity {
+        Some(Extension(identity)) => WhoAmI {
+            scopes: Scope::ALL
+                .into_iter()
+                .filter(|scope| identity.scopes.contains(scope))
+                .collect(),
+            identity: Some(identity.name),
+        },
+        None => WhoAmI {
+            identity: None,
+            scopes: Scope::ALL.to_vec(),
+        },
+    })
+}
+
+/// Transform a text by the active command
+#[utoipa::path(
+    post,
+    path = "/request-reply/{message}",
+    tag = "transform",
+    params(("message" = String, Path, description = "Text to transform"), Timeout),
+    responses((
+        status = 200,
+        description = "Transformed text",
+        body = String,
+        headers(
+            ("X-Cache" = String, description = "`HIT` or `MISS` if the cache is on"),
+            ("X-Processed-By" = String, description = "`fallback` if no worker answered"),
+        ),
+    )),
+)]
 pub async fn request_reply(
     Path(message): Path<String>,
     State(nats): State<Arc<Nats>>,
+    Timeout(timeout): Timeout,
+) -> Result<impl IntoResponse> {
+    let transformed = nats.request(message, timeout).await?;
+
+    Ok((
+        cache_header(transformed.cache),
+        fallback_header(transformed.fallback),
+        transformed.output,
+    ))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct TransformResponse {
+    output: String,
+    #[schema(value_type = CommandCode)]
+    command: &'static str,
+    worker_id: String,
+    elapsed_us: u128,
+    #[serde(skip)]
+    cache: Option<CacheStatus>,
+    #[serde(skip)]
+    fallback: bool,
+}
+
+/// Transform a text, optionally by a command other than the active one
+#[utoipa::path(
+    post,
+    path = "/v1/transform",
+    tag = "transform",
+    params(Timeout),
+    request_body(content(
+        (TransformRequest = "application/json"),
+        (String = "text/plain"),
+    )),
+    responses((
+        status = 200,
+        description = "Transformed text",
+        body = TransformResponse,
+        headers(
+            ("X-Cache" = String, description = "`HIT` or `MISS` if the cache is on"),
+            ("X-Processed-By" = String, description = "`fallback` if no worker answered"),
+        ),
+    )),
+)]
+pub async fn transform(
+    State(nats): State<Arc<Nats>>,
+    Timeout(timeout): Timeout,
+    request: TransformRequest,
 ) -> Result<impl IntoResponse> {
-    Ok(nats.request(message).await?)
+    let response = transform_one(&nats, request, timeout).await?;
+
+    Ok((
+        cache_header(response.cache),
+        fallback_header(response.fallback),
+        Json(response),
+    ))
+}
+
+fn cache_header(cache: Option<CacheStatus>) -> Option<[(HeaderName, &'static str); 1]> {
+    cache.map(|cache| [(HeaderName::from_static(X_CACHE), cache.as_str())])
+}
+
+fn fallback_header(fallback: bool) -> Option<[(HeaderName, &'static str); 1]> {
+    fallback.then(|| [(HeaderName::from_static(X_PROCESSED_BY), "fallback")])
 }
 
+pub async fn transform_one(
+    nats: &Nats,
+    request: TransformRequest,
+    timeout: Duration,
+) -> Result<TransformResponse> {
+    let command = request.command.map(String::try_into).transpose()?;
+
+    let start = Instant::now();
+    let transformed = nats.transform(request.input, command, timeout).await?;
+
+    Ok(TransformResponse {
+        output: transformed.output,
+        command: transformed.command.code(),
+        worker_id: transformed.worker_id,
+        elapsed_us: start.elapsed().as_micros(),
+        cache: transformed.cache,
+        fallback: transformed.fallback,
+    })
+}
+
+#[derive(Serialize, ToSchema)]
+#[serde(tag = "status", rename_all = "lowercase")]
+pub enum BatchItemResponse {
+    Ok(TransformResponse),
+    Error(ErrorDetail),
+}
+
+/// Transform many texts at once
+///
+/// Every item fails alone, results come in the order of the items. Every item takes a
+/// token of the rate limit.
+#[utoipa::path(
+    post,
+    path = "/v1/transform/batch",
+    tag = "transform",
+    params(Timeout),
+    request_body(content(
+        (Vec<BatchItem> = "application/json"),
+        (BatchItem = "application/x-ndjson"),
+    )),
+    responses((status = 200, description = "Results of the items", content(
+        (Vec<BatchItemResponse> = "application/json"),
+        (BatchItemResponse = "application/x-ndjson"),
+    ))),
+)]
+pub async fn transform_batch(
+    State(nats): State<Arc<Nats>>,
+    State(config): State<Arc<Config>>,
+    Timeout(timeout): Timeout,
+    charge: Option<Extension<Charge>>,
+    batch: BatchRequest,
+) -> Result<Response> {
+    info!("Transform a batch of {} items", batch.items.len());
+
+    if let Some(Extension(charge)) = charge {
+        charge.units(batch.items.len())?;
+    }
+
+    let results = transform_items(batch.items, config.batch_concurrency, move |request| {
+        let nats = Arc::clone(&nats);
+        async move { transform_one(&nats, request, timeout).await }
+    });
+
+    if batch.ndjson {
+        Ok((
+            [(CONTENT_TYPE, "application/x-ndjson")],
+            StreamBody::new(results.map(|result| {
+                let mut line = serde_json::to_vec(&result)?;
+                line.push(b'\n');
+                Ok::<_, serde_json::Error>(line)
+            })),
+        )
+            .into_response())
+    } else {
+        Ok(Json(results.collect::<Vec<_>>().await).into_response())
+    }
+}
+
+/// Results of the items in their order, up to `concurrency` of them transformed at once
+fn transform_items<F, Fut>(
+    items: Vec<Result<TransformRequest, ApiError>>,
+    concurrency: usize,
+    transform: F,
+) -> impl futures::Stream<Item = BatchItemResponse>
+where
+    F: Fn(TransformRequest) -> Fut,
+    Fut: Future<Output = Result<TransformResponse>>,
+{
+    stream::iter(items.into_iter().enumerate())
+        .map(move |(index, item)| {
+            let item = item.map(&transform);
+
+            async move {
+                let result = match item {
+                    Ok(transformed) => transformed.await.map_err(ErrorDetail::from),
+                    Err(e) => Err(e.into()),
+                };
+
+                result.map_or_else(
+                    |error| {
+                        warn!("Item #{} failed: {}", index, error.detail);
+                        BatchItemResponse::Error(error)
+                    },
+                    BatchItemResponse::Ok,
+                )
+            }
+        })
+        .buffered(concurrency)
+}
+
+/// Change the command applied by the fleet
+///
+/// A change repeating the `Idempotency-Key` of an earlier one is dropped and answered
+/// like the earlier one was. A change with `If-Match` fails with `412` once another
+/// command has been stored in the meantime.
+#[utoipa::path(
+    post,
+    path = "/fnf/{command}",
+    tag = "admin",
+    params(
+        ("command" = CommandCode, Path, description = "Command to apply"),
+        Preconditions,
+    ),
+    responses((
+        status = 200,
+        description = "The command is stored",
+        headers(
+            ("ETag" = String, description = "Sequence of the stored command"),
+            ("Idempotent-Replayed" = bool, description = "The change was a duplicate"),
+        ),
+    )),
+)]
 pub async fn fire_and_forget(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    identity: Option<Extension<Identity>>,
     Path(command): Path<String>,
     State(nats): State<Arc<Nats>>,
+    preconditions: Preconditions,
 ) -> Result<impl IntoResponse> {
-    Ok(nats.publish(command.try_into()?).await?)
+    let published = nats
+        .publish(
+            command.try_into()?,
+            Issuer {
+                client_ip: Some(addr.ip().to_string()),
+                identity: identity.map(|Extension(identity)| identity.name),
+            },
+            preconditions,
+        )
+        .await?;
+
+    Ok([
+        (ETAG, entity_tag(published.sequence)),
+        (
+            HeaderName::from_static(IDEMPOTENT_REPLAYED),
+            published.duplicate.to_string(),
+        ),
+    ])
+}
+
+/// Strong entity tag of a command by its sequence
+fn entity_tag(sequence: u64) -> String {
+    format!("\"{}\"", sequence)
+}
+
+/// The command applied by the fleet
+#[derive(Serialize, ToSchema)]
+pub struct ActiveCommand {
+    #[serde(with = "command::code")]
+    #[schema(value_type = CommandCode)]
+    command: Command,
+    /// Sequence of the command in the `COMMANDS` stream, none for the default one
+    sequence: Option<u64>,
+    #[serde(with = "time::serde::rfc3339::option")]
+    at: Option<OffsetDateTime>,
+}
+
+impl From<Option<CommandEvent>> for ActiveCommand {
+    fn from(event: Option<CommandEvent>) -> Self {
+        match event {
+            Some(event) => Self {
+                command: event.command,
+                sequence: Some(event.sequence),
+                at: Some(event.at),
+            },
+            None => Self {
+                command: Command::default(),
+                sequence: None,
+                at: None,
+            },
+        }
+    }
+}
+
+/// The command applied by the fleet
+///
+/// The `ETag` of a stored command is what `If-Match` of a change replacing it takes.
+#[utoipa::path(
+    get,
+    path = "/v1/command",
+    tag = "transform",
+    responses((
+        status = 200,
+        description = "The active command",
+        body = ActiveCommand,
+        headers(("ETag" = String, description = "Sequence of the stored command, if any")),
+    )),
+)]
+pub async fn active_command(State(nats): State<Arc<Nats>>) -> Result<impl IntoResponse> {
+    let active = ActiveCommand::from(nats.active_command().await?);
+    let etag = active
+        .sequence
+        .map(|sequence| [(ETAG, entity_tag(sequence))]);
+
+    Ok((etag, Json(active)))
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WorkerCommandsResponse {
+    active: ActiveCommand,
+    workers: Vec<WorkerCommandResponse>,
+}
+
+#[derive(Serialize, ToSchema)]
+pub struct WorkerCommandResponse {
+    #[serde(flatten)]
+    worker: WorkerCommand,
+    /// The worker holds a command other than the active one
+    drift: bool,
+}
+
+/// The active command and the commands held by the workers
+#[utoipa::path(
+    get,
+    path = "/v1/command/workers",
+    tag = "transform",
+    responses((status = 200, description = "Commands of the workers", body = WorkerCommandsResponse)),
+)]
+pub async fn worker_commands(State(nats): State<Arc<Nats>>) -> Result<impl IntoResponse> {
+    let (active, workers) = tokio::try_join!(nats.active_command(), nats.worker_commands())?;
+    let active = ActiveCommand::from(active);
+
+    let workers = workers
+        .into_iter()
+        .map(|worker| {
+            let drift = worker.command != active.command;
+            if drift {
+                warn!("Worker #{} holds {:?}", worker.worker_id, worker.command);
+            }
+
+            WorkerCommandResponse { worker, drift }
+        })
+        .collect();
+
+    Ok(Json(WorkerCommandsResponse { active, workers }))
+}
+
+/// Commands after a sequence which a client has missed, as they are gone from the history
+#[derive(Serialize, ToSchema)]
+pub struct CommandsReset {
+    /// Sequence the client asked to resume after
+    after: u64,
+}
+
+/// Stream commands as they are stored
+///
+/// Every `command` event carries a command event as JSON and its sequence as ID. A client
+/// resuming after a command which is gone from the history gets a `reset` event with the
+/// sequence it sent, followed by the active command only.
+#[utoipa::path(
+    get,
+    path = "/v1/commands/events",
+    tag = "transform",
+    params((
+        "Last-Event-ID" = Option<u64>,
+        Header,
+        description = "Sequence of the last command received, to resume after it",
+    )),
+    responses((
+        status = 200,
+        description = "Server-sent events",
+        body = CommandEvent,
+        content_type = "text/event-stream",
+    )),
+)]
+pub async fn command_events(
+    headers: HeaderMap,
+    State(nats): State<Arc<Nats>>,
+    State(shutdown): State<Shutdown>,
+) -> Result<impl IntoResponse> {
+    let last_event_id = headers
+        .get(LAST_EVENT_ID)
+        .map(|value| {
+            value
+                .to_str()
+                .ok()
+                .and_then(|value| value.parse::<u64>().ok())
+                .ok_or(ApiError::InvalidHeader(
+                    "Last-Event-ID must be a sequence number",
+                ))
+        })
+        .transpose()?;
+    info!("Stream command events after {:?}", last_event_id);
+
+    let mut reset = None;
+    if let Some(sequence) = last_event_id {
+        if nats.commands_missed_after(sequence).await? {
+            warn!("Commands after {} are gone, reset the client", sequence);
+            reset = Some(
+                Event::default()
+                    .event("reset")
+                    .json_data(CommandsReset { after: sequence })?,
+            );
+        }
+    }
+
+    let events = nats
+        .commands_after(last_event_id.filter(|_| reset.is_none()))
+        .await?
+        .map(|event| {
+            let event = event?;
+
+            Ok::<_, color_eyre::Report>(
+                Event::default()
+                    .id(event.sequence.to_string())
+                    .event("command")
+                    .json_data(event)?,
+            )
+        })
+        // Let the server shut down, clients reconnect with `Last-Event-ID` anyway
+        .take_until(async move { shutdown.requested().await });
+    let events = stream::iter(reset.map(Ok)).chain(events);
+
+    Ok(Sse::new(events).keep_alive(KeepAlive::default()))
+}
+
+#[derive(Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct AuditQuery {
+    /// Skip records appended before that moment
+    #[serde(default, with = "time::serde::rfc3339::option")]
+    since: Option<OffsetDateTime>,
+    /// Max number of records, 1 to 1000, 1000 by default
+    #[param(minimum = 1, maximum = 1000)]
+    limit: Option<usize>,
+}
+
+impl AuditQuery {
+    fn limit(&self) -> Result<usize, ApiError> {
+        match self.limit {
+            None => Ok(AUDIT_MAX_LIMIT),
+            Some(limit @ 1..=AUDIT_MAX_LIMIT) => Ok(limit),
+            Some(limit) => Err(ApiError::InvalidQuery(format!(
+                "limit {} is not within 1..={}",
+                limit, AUDIT_MAX_LIMIT
+            ))),
+        }
+    }
+}
+
+/// Records of the audit trail
+#[utoipa::path(
+    get,
+    path = "/audit",
+    tag = "admin",
+    params(AuditQuery),
+    responses((status = 200, description = "Audit records", body = Vec<Record>)),
+)]
+pub async fn audit(
+    Query(query): Query<AuditQuery>,
+    State(nats): State<Arc<Nats>>,
+) -> Result<impl IntoResponse> {
+    Ok(Json(nats.audit(query.since, query.limit()?).await?))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+    use axum::extract::FromRequestParts;
+
+    fn request(input: &str) -> Result<TransformRequest, ApiError> {
+        Ok(TransformRequest {
+            input: input.to_string(),
+            command: None,
+        })
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn batches_are_bounded_and_ordered() {
+        let in_flight = Arc::new(AtomicUsize::new(0));
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let items = vec![
+            request("a"),
+            Err(ApiError::InvalidBody("Not an item".to_string())),
+            request("bb"),
+            request("ccc"),
+            request("dddd"),
+        ];
+
+        let results = transform_items(items, 2, |request| {
+            let in_flight = Arc::clone(&in_flight);
+            let most_in_flight = Arc::clone(&most_in_flight);
+
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                // Later items are done sooner
+                let delay = 100 / request.input.len() as u64;
+                tokio::time::sleep(Duration::from_millis(delay)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                Ok(TransformResponse {
+                    output: request.input,
+                    command: Command::Reverse.code(),
+                    worker_id: "1".to_string(),
+                    elapsed_us: delay as u128 * 1000,
+                    cache: None,
+                    fallback: false,
+                })
+            }
+        })
+        .collect::<Vec<_>>()
+        .await;
+
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+        let outputs = results
+            .iter()
+            .map(|result| match result {
+                BatchItemResponse::Ok(response) => response.output.as_str(),
+                BatchItemResponse::Error(error) => error.code,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(outputs, ["a", "invalid_body", "bb", "ccc", "dddd"]);
+    }
+
+    async fn audit_limit(uri: &str) -> Result<usize, ApiError> {
+        let (mut parts, _) = axum::http::Request::get(uri).body(()).unwrap().into_parts();
+        let Query(query) = Query::<AuditQuery>::from_request_parts(&mut parts, &()).await?;
+        query.limit()
+    }
+
+    #[tokio::test]
+    async fn audit_limit_is_bounded() {
+        assert_eq!(audit_limit("/audit").await.unwrap(), AUDIT_MAX_LIMIT);
+        assert_eq!(audit_limit("/audit?limit=1").await.unwrap(), 1);
+        assert_eq!(audit_limit("/audit?limit=1000").await.unwrap(), 1000);
+
+        for uri in ["/audit?limit=0", "/audit?limit=1001", "/audit?limit=-1"] {
+            assert!(matches!(
+                audit_limit(uri).await,
+                Err(ApiError::InvalidQuery(_))
+            ));
+        }
+    }
 }
@@ -1,63 +1,765 @@
-use std::str::from_utf8;
+use std::{num::NonZeroUsize, str::from_utf8, sync::Arc, time::Duration};
 
-use crate::error::Result;
-use async_nats::{jetstream, ServerAddr};
+use crate::cache::{Cache, CacheStatus, Cached};
+use crate::error::{ApiError, Report, Result};
+use crate::extract::Preconditions;
+use crate::openapi::CommandCode;
+use crate::request_id;
+use async_nats::{
+    jetstream::{
+        self,
+        consumer::{pull, DeliverPolicy},
+        context::{Publish, PublishErrorKind},
+        stream::LastRawMessageErrorKind,
+    },
+    HeaderMap, Request, RequestErrorKind, ServerAddr,
+};
+use bytes::Bytes;
 
-use command::Command;
-use tracing::{info, debug};
+use axum::extract::FromRef;
+use color_eyre::eyre::{eyre, WrapErr};
+use command::{header, Command};
+use futures::{StreamExt, TryStreamExt};
+use health::{check, Health};
+use serde::{Deserialize, Serialize};
+use shutdown::Shutdown;
+use streams::audit::{Event, Record};
+use time::OffsetDateTime;
+use tokio::{sync::broadcast, task::JoinHandle};
+use tracing::{debug, error, info, trace, warn};
+use utoipa::ToSchema;
 
 const NATS_REQUEST_REPLY: &str = "nats.request-reply";
-const NATS_FNF: &str = "nats.fnf";
+const NATS_FNF: &str = streams::commands::SUBJECT;
+const NATS_WORKERS_COMMAND: &str = "nats.workers.command";
+const COMMAND_EVENTS_CAPACITY: usize = 16;
+const WORKERS_REPLY_TIMEOUT: Duration = Duration::from_millis(500);
+
+#[derive(Clone)]
+pub struct AppState {
+    pub nats: Arc<Nats>,
+    pub config: Arc<Config>,
+    pub shutdown: Shutdown,
+}
+
+impl FromRef<AppState> for Arc<Nats> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.nats)
+    }
+}
+
+impl FromRef<AppState> for Arc<Config> {
+    fn from_ref(state: &AppState) -> Self {
+        Arc::clone(&state.config)
+    }
+}
+
+impl FromRef<AppState> for Shutdown {
+    fn from_ref(state: &AppState) -> Self {
+        state.shutdown.clone()
+    }
+}
+
+/// Settings of the handlers
+pub struct Config {
+    pub batch_concurrency: usize,
+    pub ws_max_in_flight: usize,
+    pub ws_ping_interval: Duration,
+    pub request_timeout: Duration,
+    pub max_request_timeout: Duration,
+}
 
 pub struct Nats {
     client: async_nats::client::Client,
     jetstream: async_nats::jetstream::Context,
+    commands: jetstream::stream::Stream,
+    audit: jetstream::stream::Stream,
+    command_events: broadcast::Sender<CommandEvent>,
+    /// Task broadcasting command events and advancing the cache
+    watcher: Arc<JoinHandle<()>>,
+    cache: Option<Arc<Cache>>,
+}
+
+/// A command stored in the `COMMANDS` stream
+#[derive(Clone, Debug, Serialize, ToSchema)]
+pub struct CommandEvent {
+    pub sequence: u64,
+    #[serde(with = "command::code")]
+    #[schema(value_type = CommandCode)]
+    pub command: Command,
+    #[serde(with = "time::serde::rfc3339")]
+    pub at: OffsetDateTime,
+}
+
+/// A command held by a worker
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
+pub struct WorkerCommand {
+    pub worker_id: String,
+    #[serde(with = "command::code")]
+    #[schema(value_type = CommandCode)]
+    pub command: Command,
+    /// Sequence of the command in the `COMMANDS` stream, if it came from there
+    pub sequence: Option<u64>,
+}
+
+/// Result of a transformation made by a worker
+pub struct Transformed {
+    pub output: String,
+    pub command: Command,
+    pub worker_id: String,
+    /// Whether the output came from the cache, if it's on
+    pub cache: Option<CacheStatus>,
+    /// The processor applied the command itself as no worker answered
+    pub fallback: bool,
+}
+
+/// A command stored in the `COMMANDS` stream by a change
+pub struct Published {
+    pub sequence: u64,
+    /// The change repeated an earlier one with the same idempotency key and was dropped
+    pub duplicate: bool,
+}
+
+/// Who changes the command
+pub struct Issuer {
+    pub client_ip: Option<String>,
+    pub identity: Option<String>,
 }
 
 impl Nats {
-    pub async fn new(addr: ServerAddr) -> Result<Self> {
+    /// Connect to NATS, keeping up to `cache_capacity` outputs of transformations if any
+    pub async fn new(addr: ServerAddr, cache_capacity: Option<NonZeroUsize>) -> Result<Self> {
         info!("Connect to NATS");
         debug!("Info: {:#?}", addr);
         let client = async_nats::connect(addr).await?;
 
         let jetstream = jetstream::new(client.clone());
 
-        Ok(Self { client, jetstream })
+        let commands = jetstream
+            .get_or_create_stream(streams::commands::config())
+            .await?;
+        // A stream created by an earlier version may keep less history
+        jetstream.update_stream(streams::commands::config()).await?;
+        let audit = jetstream
+            .get_or_create_stream(streams::audit::config())
+            .await?;
+        trace!("Streams created");
+
+        // Commands stored from now on are watched, the cache starts with the active one
+        let active = last_command(&commands).await?;
+        let version = active.as_ref().map_or(0, |active| active.sequence);
+        let cache = cache_capacity.map(|capacity| {
+            info!("Cache up to {} outputs", capacity);
+            Arc::new(Cache::new(
+                capacity,
+                version,
+                active.map(|active| active.command).unwrap_or_default(),
+            ))
+        });
+
+        let (command_events, _) = broadcast::channel(COMMAND_EVENTS_CAPACITY);
+        let watcher = tokio::spawn({
+            let commands = commands.clone();
+            let command_events = command_events.clone();
+            let cache = cache.clone();
+
+            async move {
+                match watch_commands(commands, version, command_events, cache).await {
+                    Ok(()) => error!("Command watcher stopped"),
+                    Err(e) => error!("Command watcher failed: {:?}", e),
+                }
+            }
+        });
+
+        Ok(Self {
+            client,
+            jetstream,
+            commands,
+            audit,
+            command_events,
+            watcher: Arc::new(watcher),
+            cache,
+        })
     }
 
-    pub async fn request(&self, message: String) -> Result<String> {
+    pub fn health(&self) -> Health {
+        Health::new()
+            .ready("nats", check::nats(self.client.clone()))
+            .ready("jetstream", check::jetstream(self.jetstream.clone()))
+            .ready(
+                "commands",
+                check::task(Arc::clone(&self.watcher), "Command watcher is dead"),
+            )
+    }
+
+    pub async fn request(&self, message: String, timeout: Duration) -> Result<Transformed> {
+        self.transform(message, None, timeout).await
+    }
+
+    /// Send the input to be transformed by a worker, optionally overriding its command
+    ///
+    /// The timeout is passed on in a header, so that the hops on the way to a worker
+    /// give up once it has expired as well. Outputs are taken from and kept in the cache
+    /// if it's on, unless the processor has fallen back on applying the command itself.
+    /// The cache is bypassed once the command watcher is dead, as it can't tell when
+    /// the outputs get stale anymore.
+    pub async fn transform(
+        &self,
+        input: String,
+        command: Option<Command>,
+        timeout: Duration,
+    ) -> Result<Transformed> {
+        let Some(cache) = self.cache.as_ref().filter(|_| !self.watcher.is_finished()) else {
+            return self.transform_by_worker(input, command, timeout).await;
+        };
+
+        let version = cache.version();
+        if let Some(cached) = cache.get(command, &input) {
+            debug!("Cached output: {}", cached.output);
+            return Ok(Transformed {
+                output: cached.output,
+                command: cached.command,
+                worker_id: cached.worker_id,
+                cache: Some(CacheStatus::Hit),
+                fallback: false,
+            });
+        }
+
+        let key = input.clone();
+        let transformed = self.transform_by_worker(input, command, timeout).await?;
+        // Workers take over again as soon as they are back
+        if !transformed.fallback {
+            cache.put(
+                version,
+                command,
+                &key,
+                Cached {
+                    output: transformed.output.clone(),
+                    command: transformed.command,
+                    worker_id: transformed.worker_id.clone(),
+                },
+            );
+        }
+
+        Ok(Transformed {
+            cache: Some(CacheStatus::Miss),
+            ..transformed
+        })
+    }
+
+    async fn transform_by_worker(
+        &self,
+        input: String,
+        command: Option<Command>,
+        timeout: Duration,
+    ) -> Result<Transformed> {
         info!("Send a request to {}", NATS_REQUEST_REPLY);
-        debug!("Request payload: {}", message);
-
-        let res = from_utf8(
-            &self
-                .client
-                .request(NATS_REQUEST_REPLY, message.into())
-                .await?
-                .payload,
-        )?
-        .to_string();
+        debug!(
+            "Request payload: {}, command: {:?}, timeout: {:?}",
+            input, command, timeout
+        );
+
+        let mut headers = HeaderMap::new();
+        if let Some(command) = command {
+            headers.insert(header::COMMAND, command.code());
+        }
+        headers.insert(header::TIMEOUT, timeout.as_millis().to_string().as_str());
+        if let Some(id) = request_id::current() {
+            headers.insert(header::REQUEST_ID, id.as_str());
+        }
+
+        let res = self
+            .client
+            .send_request(
+                NATS_REQUEST_REPLY,
+                Request::new()
+                    .headers(headers)
+                    .payload(input.into())
+                    .timeout(Some(timeout)),
+            )
+            .await
+            .map_err(|e| match e.kind() {
+                RequestErrorKind::TimedOut => ApiError::TimedOut(timeout).into(),
+                RequestErrorKind::NoResponders => ApiError::NoResponders.into(),
+                RequestErrorKind::Other => Report::from(eyre!(e).wrap_err(ApiError::Nats)),
+            })?;
+
+        let headers = res.headers.unwrap_or_default();
+        if let Some(failure) = reply_failure(&headers, timeout) {
+            warn!("The processor replied with a failure: {}", failure);
+            return Err(failure.into());
+        }
+
+        let output = from_utf8(&res.payload).map_err(malformed)?.to_string();
+        let command = headers
+            .get(header::COMMAND)
+            .ok_or_else(|| ApiError::MalformedMessage("no command found in the reply".into()))?
+            .to_string()
+            .try_into()
+            .map_err(|e: command::CommandError| ApiError::MalformedMessage(e.to_string()))?;
+        let worker_id = headers
+            .get(header::WORKER_ID)
+            .ok_or_else(|| ApiError::MalformedMessage("no worker ID found in the reply".into()))?
+            .to_string();
+        let fallback = headers.get(header::PROCESSED_BY).is_some();
 
         info!("Got a response from {}", NATS_REQUEST_REPLY);
-        debug!("Response payload: {}", res);
+        debug!(
+            "Response payload: {}, worker: {}, fallback: {}",
+            output, worker_id, fallback
+        );
 
-        Ok(res)
+        Ok(Transformed {
+            output,
+            command,
+            worker_id,
+            cache: None,
+            fallback,
+        })
     }
 
-    pub async fn publish(&self, command: Command) -> Result<()> {
+    /// Store the command unless the preconditions of the change fail
+    ///
+    /// A duplicate is dropped by JetStream within the duplicate window of the stream and
+    /// comes back with the sequence of the original change. The change is audited again
+    /// then, which the audit trail drops as well unless the audit of the original failed.
+    pub async fn publish(
+        &self,
+        command: Command,
+        issuer: Issuer,
+        preconditions: Preconditions,
+    ) -> Result<Published> {
         info!("Publishing to {}", NATS_FNF);
-        debug!("Message payload: {:?}", command);
+        debug!(
+            "Message payload: {:?}, preconditions: {:?}",
+            command, preconditions
+        );
 
-        self.jetstream
-            .publish(
-                NATS_FNF,
-                serde_json::to_vec(&serde_json::json!(&command))?.into(),
-            )
-            .await?
+        let mut publish =
+            Publish::build().payload(serde_json::to_vec(&serde_json::json!(&command))?.into());
+        if let Some(key) = &preconditions.idempotency_key {
+            publish = publish.message_id(key);
+        }
+        if let Some(sequence) = preconditions.expected_sequence {
+            publish = publish.expected_last_sequence(sequence);
+        }
+
+        let ack = self
+            .jetstream
+            .send_publish(NATS_FNF, publish)
+            .await
+            .wrap_err(ApiError::PublishFailed)?
+            .await
+            .map_err(|e| match (e.kind(), preconditions.expected_sequence) {
+                (PublishErrorKind::WrongLastSequence, Some(sequence)) => {
+                    ApiError::PreconditionFailed(sequence).into()
+                }
+                _ => Report::from(eyre!(e).wrap_err(ApiError::PublishFailed)),
+            })?;
+
+        if ack.duplicate {
+            info!("Dropped a duplicate of sequence {}", ack.sequence);
+        } else {
+            info!("Published to {}", NATS_FNF);
+        }
+
+        // The command replaced is the one stored right before, which the expected last
+        // sequence, if any, has been checked against
+        let old = command_at(&self.commands, ack.sequence - 1).await?;
+
+        trace!("Audit the change");
+        streams::audit::publish(
+            &self.jetstream,
+            Event::Issued {
+                client_ip: issuer.client_ip,
+                identity: issuer.identity,
+                old,
+                new: command,
+                sequence: ack.sequence,
+            },
+        )
+        .await
+        .wrap_err(ApiError::AuditFailed)?;
+
+        Ok(Published {
+            sequence: ack.sequence,
+            duplicate: ack.duplicate,
+        })
+    }
+
+    /// Receive commands stored in the `COMMANDS` stream from now on
+    pub fn command_events(&self) -> broadcast::Receiver<CommandEvent> {
+        self.command_events.subscribe()
+    }
+
+    /// Whether commands stored after the given sequence are gone from the `COMMANDS`
+    /// stream, as it keeps only the last [streams::commands::HISTORY] of them
+    pub async fn commands_missed_after(&self, sequence: u64) -> Result<bool> {
+        let mut stream = self.commands.clone();
+        let state = &stream.info().await.wrap_err(ApiError::Nats)?.state;
+        debug!("Commands stream state: {:?}", state);
+
+        Ok(missed_after(sequence, state.messages, state.first_sequence))
+    }
+
+    /// Commands stored in the `COMMANDS` stream after the given sequence
+    ///
+    /// Starts with the last stored command if no sequence is given.
+    pub async fn commands_after(
+        &self,
+        sequence: Option<u64>,
+    ) -> Result<impl futures::Stream<Item = color_eyre::Result<CommandEvent>>> {
+        debug!("Read commands after {:?}", sequence);
+
+        command_stream(
+            &self.commands,
+            sequence.map_or(DeliverPolicy::Last, |sequence| {
+                DeliverPolicy::ByStartSequence {
+                    start_sequence: sequence + 1,
+                }
+            }),
+        )
+        .await
+    }
+
+    /// The last command stored in the `COMMANDS` stream, if any
+    pub async fn active_command(&self) -> Result<Option<CommandEvent>> {
+        last_command(&self.commands).await
+    }
+
+    /// Commands held by the workers which answered in time
+    pub async fn worker_commands(&self) -> Result<Vec<WorkerCommand>> {
+        info!("Ask workers for their commands");
+
+        let inbox = self.client.new_inbox();
+        let replies = self
+            .client
+            .subscribe(inbox.clone())
+            .await
+            .wrap_err(ApiError::Nats)?;
+        self.client
+            .publish_with_reply(NATS_WORKERS_COMMAND, inbox, Bytes::new())
+            .await
+            .wrap_err(ApiError::Nats)?;
+
+        let workers = collect_workers(replies, WORKERS_REPLY_TIMEOUT).await?;
+        debug!("Workers: {:?}", workers);
+
+        Ok(workers)
+    }
+
+    /// Records of the audit trail appended since the given moment
+    pub async fn audit(&self, since: Option<OffsetDateTime>, limit: usize) -> Result<Vec<Record>> {
+        info!("Read the audit trail");
+        debug!("Since: {:?}, limit: {}", since, limit);
+
+        let consumer = self
+            .audit
+            .create_consumer(pull::Config {
+                deliver_policy: since.map_or(DeliverPolicy::All, |start_time| {
+                    DeliverPolicy::ByStartTime { start_time }
+                }),
+                ack_policy: jetstream::consumer::AckPolicy::None,
+                inactive_threshold: Duration::from_secs(5),
+                ..Default::default()
+            })
+            .await
+            .wrap_err(ApiError::Nats)?;
+
+        let records = consumer
+            .fetch()
+            .max_messages(limit)
+            .messages()
+            .await
+            .wrap_err(ApiError::Nats)?
+            .map_err(|e| eyre!(e).wrap_err(ApiError::Nats))
+            .and_then(|msg| async move {
+                Ok(serde_json::from_slice::<Record>(&msg.payload).map_err(malformed)?)
+            })
+            .try_collect::<Vec<_>>()
             .await?;
 
-        info!("Published to {}", NATS_FNF);
+        debug!("Read {} records", records.len());
+
+        Ok(records)
+    }
+}
+
+/// The failure a processor has replied with instead of a response, if any
+fn reply_failure(headers: &HeaderMap, timeout: Duration) -> Option<ApiError> {
+    let status = headers.get(header::STATUS)?;
+
+    Some(match status.as_str() {
+        "503" => ApiError::NoWorkers,
+        "408" => ApiError::TimedOut(timeout),
+        _ => ApiError::WorkerFailed(
+            headers
+                .get(header::DESCRIPTION)
+                .map_or_else(|| format!("status {}", status), ToString::to_string),
+        ),
+    })
+}
+
+async fn last_command(stream: &jetstream::stream::Stream) -> Result<Option<CommandEvent>> {
+    match stream.get_last_raw_message_by_subject(NATS_FNF).await {
+        Ok(raw) => {
+            let (sequence, at) = (raw.sequence, raw.time);
+            let msg = async_nats::Message::try_from(raw)
+                .map_err(|e| ApiError::MalformedMessage(e.to_string()))?;
+
+            Ok(Some(CommandEvent {
+                sequence,
+                command: serde_json::from_slice(&msg.payload).map_err(malformed)?,
+                at,
+            }))
+        }
+        Err(e) if e.kind() == LastRawMessageErrorKind::NoMessageFound => Ok(None),
+        Err(e) => Err(eyre!(e).wrap_err(ApiError::Nats).into()),
+    }
+}
+
+/// Command stored at the sequence, none before the first one
+async fn command_at(stream: &jetstream::stream::Stream, sequence: u64) -> Result<Option<Command>> {
+    if sequence == 0 {
+        return Ok(None);
+    }
+
+    let raw = stream
+        .get_raw_message(sequence)
+        .await
+        .map_err(|e| eyre!(e).wrap_err(ApiError::Nats))?;
+    let msg = async_nats::Message::try_from(raw)
+        .map_err(|e| ApiError::MalformedMessage(e.to_string()))?;
+
+    Ok(Some(
+        serde_json::from_slice(&msg.payload).map_err(malformed)?,
+    ))
+}
+
+/// A message of NATS which doesn't parse, unlike a body of a client
+fn malformed(e: impl std::fmt::Display) -> ApiError {
+    ApiError::MalformedMessage(e.to_string())
+}
+
+/// Commands of the workers which replied within the window
+async fn collect_workers(
+    replies: impl futures::Stream<Item = async_nats::Message>,
+    window: Duration,
+) -> Result<Vec<WorkerCommand>> {
+    Ok(replies
+        .take_until(tokio::time::sleep(window))
+        // Skip a status message, e.g. when there are no workers at all
+        .filter(|msg| futures::future::ready(msg.status.is_none()))
+        .map(|msg| serde_json::from_slice::<WorkerCommand>(&msg.payload).map_err(malformed))
+        .try_collect::<Vec<_>>()
+        .await?)
+}
+
+/// Whether commands after the sequence have been dropped already, given how many messages
+/// the stream has and the sequence of the first of them
+fn missed_after(sequence: u64, messages: u64, first_sequence: u64) -> bool {
+    messages > 0 && sequence + 1 < first_sequence
+}
+
+/// Broadcast commands stored after the given sequence and advance the cache by them
+async fn watch_commands(
+    stream: jetstream::stream::Stream,
+    sequence: u64,
+    events: broadcast::Sender<CommandEvent>,
+    cache: Option<Arc<Cache>>,
+) -> Result<()> {
+    info!("Start watching commands");
+
+    let commands = command_stream(
+        &stream,
+        DeliverPolicy::ByStartSequence {
+            start_sequence: sequence + 1,
+        },
+    )
+    .await?;
+
+    follow_commands(commands, events, cache).await
+}
+
+/// Apply command events as they come until their stream ends or breaks
+async fn follow_commands(
+    commands: impl futures::Stream<Item = color_eyre::Result<CommandEvent>>,
+    events: broadcast::Sender<CommandEvent>,
+    cache: Option<Arc<Cache>>,
+) -> Result<()> {
+    futures::pin_mut!(commands);
+    while let Some(event) = commands.next().await {
+        let event = event?;
+        debug!("Command event: {:?}", event);
+
+        if let Some(cache) = &cache {
+            cache.advance(event.sequence, event.command);
+        }
+
+        // Nobody may be listening at the moment
+        let _ = events.send(event);
+    }
+
+    Ok(())
+}
+
+/// Commands of the `COMMANDS` stream read by an ordered consumer
+async fn command_stream(
+    stream: &jetstream::stream::Stream,
+    deliver_policy: DeliverPolicy,
+) -> Result<impl futures::Stream<Item = color_eyre::Result<CommandEvent>>> {
+    let messages = stream
+        .create_consumer(pull::OrderedConfig {
+            deliver_policy,
+            ..Default::default()
+        })
+        .await
+        .wrap_err(ApiError::Nats)?
+        .messages()
+        .await
+        .wrap_err(ApiError::Nats)?;
+
+    Ok(messages.map(|msg| {
+        let msg = msg?;
+        let info = msg.info().map_err(|e| eyre!(e))?;
+
+        Ok(CommandEvent {
+            sequence: info.stream_sequence,
+            command: serde_json::from_slice(&msg.payload).map_err(malformed)?,
+            at: info.published,
+        })
+    }))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use async_nats::StatusCode;
+
+    fn failure(status: &str, description: &str) -> Option<ApiError> {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::STATUS, status);
+        headers.insert(header::DESCRIPTION, description);
+
+        reply_failure(&headers, Duration::from_secs(5))
+    }
+
+    #[test]
+    fn missed_commands() {
+        // Nothing stored yet
+        assert!(!missed_after(0, 0, 0));
+        // The next command is still there
+        assert!(!missed_after(4, 10, 5));
+        assert!(!missed_after(7, 10, 5));
+        // Commands 5 and 6 are gone
+        assert!(missed_after(4, 10, 7));
+    }
+
+    fn event(sequence: u64, command: Command) -> color_eyre::Result<CommandEvent> {
+        Ok(CommandEvent {
+            sequence,
+            command,
+            at: OffsetDateTime::now_utc(),
+        })
+    }
+
+    #[tokio::test]
+    async fn command_changes_invalidate_cache() {
+        let cache = Arc::new(Cache::new(
+            NonZeroUsize::new(2).unwrap(),
+            1,
+            Command::Reverse,
+        ));
+        cache.put(
+            1,
+            None,
+            "abc",
+            Cached {
+                output: "cba".to_string(),
+                command: Command::Reverse,
+                worker_id: "1".to_string(),
+            },
+        );
+        let (events, mut received) = broadcast::channel(COMMAND_EVENTS_CAPACITY);
+
+        let commands = futures::stream::iter([event(2, Command::ToUpperCase)]);
+        follow_commands(commands, events, Some(Arc::clone(&cache)))
+            .await
+            .unwrap();
+
+        assert!(cache.get(None, "abc").is_none());
+        assert_eq!(cache.version(), 2);
+        assert_eq!(received.recv().await.unwrap().sequence, 2);
+    }
+
+    #[tokio::test]
+    async fn watcher_fails_on_broken_commands() {
+        let (events, _) = broadcast::channel(COMMAND_EVENTS_CAPACITY);
+        let commands = futures::stream::iter([event(2, Command::Reverse), Err(eyre!("gone"))]);
+
+        assert!(follow_commands(commands, events, None).await.is_err());
+    }
+
+    fn reply(worker_id: &str, status: Option<StatusCode>) -> async_nats::Message {
+        let payload = serde_json::json!({
+            "worker_id": worker_id,
+            "command": "rev",
+            "sequence": 3,
+        })
+        .to_string();
+
+        async_nats::Message {
+            subject: "inbox".into(),
+            reply: None,
+            length: payload.len(),
+            payload: payload.into(),
+            headers: None,
+            status,
+            description: None,
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn workers_reply_within_window() {
+        let replies = futures::stream::iter([
+            (0, reply("1", None)),
+            (0, reply("", Some(StatusCode::NO_RESPONDERS))),
+            (400, reply("2", None)),
+            (600, reply("3", None)),
+        ])
+        .then(|(delay, msg)| async move {
+            tokio::time::sleep(Duration::from_millis(delay)).await;
+            msg
+        });
+
+        let workers = collect_workers(replies, WORKERS_REPLY_TIMEOUT)
+            .await
+            .unwrap();
+
+        let ids = workers
+            .iter()
+            .map(|worker| worker.worker_id.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(ids, ["1", "2"]);
+        assert_eq!(workers[0].command, Command::Reverse);
+        assert_eq!(workers[0].sequence, Some(3));
+    }
+
+    #[test]
+    fn reply_failures() {
+        assert!(reply_failure(&HeaderMap::new(), Duration::from_secs(5)).is_none());
 
-        Ok(())
+        assert!(matches!(
+            failure("503", "No worker"),
+            Some(ApiError::NoWorkers)
+        ));
+        assert!(matches!(
+            failure("408", "Too late"),
+            Some(ApiError::TimedOut(timeout)) if timeout == Duration::from_secs(5)
+        ));
+        assert!(matches!(
+            failure("500", "Broken pipe"),
+            Some(ApiError::WorkerFailed(description)) if description == "Broken pipe"
+        ));
     }
 }
@@ -0,0 +1,338 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use crate::error::Result;
+use axum::{
+    extract::connect_info::IntoMakeServiceWithConnectInfo, middleware::AddExtension, Extension,
+    Router,
+};
+use axum_server::{
+    accept::Accept,
+    tls_rustls::{RustlsAcceptor, RustlsConfig},
+    Handle,
+};
+use color_eyre::eyre::{eyre, Context};
+use futures::future::BoxFuture;
+use rustls::{
+    server::AllowAnyAnonymousOrAuthenticatedClient, Certificate, PrivateKey, RootCertStore,
+    ServerConfig,
+};
+use rustls_pemfile::Item;
+use shutdown::Shutdown;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio_rustls::server::TlsStream;
+use tower::Layer;
+use tracing::{debug, info, trace, warn};
+
+/// How often the files are checked for changes
+const RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Common name of the subject of the certificate a client has presented
+#[derive(Debug, Clone)]
+pub struct ClientCert(pub String);
+
+/// Files the TLS listener is configured by
+pub struct Tls {
+    pub cert: PathBuf,
+    pub key: PathBuf,
+    /// CA bundle verifying certificates of clients, if any
+    pub client_ca: Option<PathBuf>,
+}
+
+impl Tls {
+    fn server_config(&self) -> Result<Arc<ServerConfig>> {
+        let builder = ServerConfig::builder().with_safe_defaults();
+
+        let builder = match &self.client_ca {
+            Some(path) => {
+                let mut roots = RootCertStore::empty();
+                for cert in read_certs(path)? {
+                    roots.add(&cert)?;
+                }
+                debug!("Verify client certificates by {} CAs", roots.len());
+
+                // Clients without a certificate authenticate by other means
+                builder.with_client_cert_verifier(
+                    AllowAnyAnonymousOrAuthenticatedClient::new(roots).boxed(),
+                )
+            }
+            None => builder.with_no_client_auth(),
+        };
+
+        let mut config = builder.with_single_cert(read_certs(&self.cert)?, read_key(&self.key)?)?;
+        config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+
+        Ok(Arc::new(config))
+    }
+
+    /// Last modification times of the files
+    fn modified(&self) -> io::Result<Vec<SystemTime>> {
+        [Some(&self.cert), Some(&self.key), self.client_ca.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|path| fs::metadata(path)?.modified())
+            .collect()
+    }
+
+    /// Reload the configuration whenever any of the files changes
+    fn watch(self, config: RustlsConfig) {
+        tokio::spawn(async move {
+            let mut modified = self.modified().ok();
+            let mut interval = tokio::time::interval(RELOAD_INTERVAL);
+
+            loop {
+                interval.tick().await;
+
+                let current = self.modified().ok();
+                if current == modified {
+                    continue;
+                }
+                modified = current;
+
+                trace!("TLS files changed");
+                match self.server_config() {
+                    Ok(server_config) => {
+                        config.reload_from_config(server_config);
+                        info!("TLS configuration reloaded");
+                    }
+                    // Files may be caught in the middle of an update
+                    Err(e) => warn!("Keep the TLS configuration: {}", e),
+                }
+            }
+        });
+    }
+
+    /// Serve the app over TLS until shutdown is requested and the connections are closed
+    pub async fn serve(
+        self,
+        bind: SocketAddr,
+        app: IntoMakeServiceWithConnectInfo<Router, SocketAddr>,
+        shutdown: Shutdown,
+        grace_period: Duration,
+    ) -> Result<()> {
+        let config = RustlsConfig::from_config(self.server_config()?);
+        self.watch(config.clone());
+
+        let handle = Handle::new();
+        tokio::spawn({
+            let handle = handle.clone();
+
+            async move {
+                shutdown.requested().await;
+                handle.graceful_shutdown(Some(grace_period));
+            }
+        });
+
+        axum_server::bind(bind)
+            .acceptor(ClientCertAcceptor {
+                inner: RustlsAcceptor::new(config),
+            })
+            .handle(handle)
+            .serve(app)
+            .await?;
+
+        Ok(())
+    }
+}
+
+fn read_certs(path: &Path) -> Result<Vec<Certificate>> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(
+        File::open(path).wrap_err_with(|| format!("opening {}", path.display()))?,
+    ))?;
+
+    Ok(certs.into_iter().map(Certificate).collect())
+}
+
+fn read_key(path: &Path) -> Result<PrivateKey> {
+    let items = rustls_pemfile::read_all(&mut BufReader::new(
+        File::open(path).wrap_err_with(|| format!("opening {}", path.display()))?,
+    ))?;
+
+    items
+        .into_iter()
+        .find_map(|item| match item {
+            Item::PKCS8Key(key) | Item::RSAKey(key) | Item::ECKey(key) => Some(PrivateKey(key)),
+            _ => None,
+        })
+        .ok_or_else(|| eyre!("No private key found in {}", path.display()).into())
+}
+
+/// Common name of the subject of a DER-encoded certificate
+fn common_name(der: &[u8]) -> Option<String> {
+    let (_, cert) = x509_parser::parse_x509_certificate(der).ok()?;
+    let common_name = cert.subject().iter_common_name().next()?.as_str().ok()?;
+
+    Some(common_name.to_string())
+}
+
+/// Terminates TLS and tells requests of a connection which certificate the client presented
+#[derive(Clone)]
+struct ClientCertAcceptor {
+    inner: RustlsAcceptor,
+}
+
+impl<I, S> Accept<I, S> for ClientCertAcceptor
+where
+    I: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    S: Send + 'static,
+{
+    type Stream = TlsStream<I>;
+    type Service = AddExtension<S, Option<ClientCert>>;
+    type Future = BoxFuture<'static, io::Result<(Self::Stream, Self::Service)>>;
+
+    fn accept(&self, stream: I, service: S) -> Self::Future {
+        let acceptor = self.inner.clone();
+
+        Box::pin(async move {
+            let (stream, service) = acceptor.accept(stream, service).await?;
+
+            let client_cert = stream
+                .get_ref()
+                .1
+                .peer_certificates()
+                .and_then(|certs| certs.first())
+                .and_then(|cert| common_name(&cert.0))
+                .map(ClientCert);
+            debug!("Client certificate: {:?}", client_cert);
+
+            Ok((stream, Extension(client_cert).layer(service)))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::{engine::general_purpose::STANDARD, Engine};
+    use rcgen::{CertificateParams, DistinguishedName, DnType};
+
+    /// Directory of a test, removed with its files once dropped
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("web-app-tls-{}-{}", std::process::id(), name));
+            fs::create_dir_all(&path).unwrap();
+            Self(path)
+        }
+
+        /// Write a file and make sure its modification time moves, as timestamps are only
+        /// as fine as the clock tick of the kernel
+        fn write(&self, name: &str, contents: &str) -> PathBuf {
+            let path = self.0.join(name);
+            let before = fs::metadata(&path).and_then(|m| m.modified()).ok();
+
+            loop {
+                fs::write(&path, contents).unwrap();
+                if fs::metadata(&path).and_then(|m| m.modified()).ok() != before {
+                    return path;
+                }
+                std::thread::sleep(Duration::from_millis(1));
+            }
+        }
+
+        /// Configuration by a certificate and its key
+        fn tls(&self, cert: &rcgen::Certificate) -> Tls {
+            Tls {
+                cert: self.write("cert.pem", &cert.serialize_pem().unwrap()),
+                key: self.write("key.pem", &cert.serialize_private_key_pem()),
+                client_ca: None,
+            }
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn cert(common_name: Option<&str>) -> rcgen::Certificate {
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]);
+        params.distinguished_name = DistinguishedName::new();
+        if let Some(common_name) = common_name {
+            params
+                .distinguished_name
+                .push(DnType::CommonName, common_name);
+        }
+
+        rcgen::Certificate::from_params(params).unwrap()
+    }
+
+    fn pem(label: &str, der: &[u8]) -> String {
+        format!(
+            "-----BEGIN {}-----\n{}\n-----END {}-----\n",
+            label,
+            STANDARD.encode(der),
+            label
+        )
+    }
+
+    #[test]
+    fn common_names() {
+        let der = cert(Some("gateway")).serialize_der().unwrap();
+        assert_eq!(common_name(&der).as_deref(), Some("gateway"));
+
+        let der = cert(None).serialize_der().unwrap();
+        assert_eq!(common_name(&der), None);
+
+        assert_eq!(common_name(b"not a certificate"), None);
+    }
+
+    #[test]
+    fn private_keys() {
+        let dir = TempDir::new("keys");
+        let cert = cert(Some("server"));
+
+        // An EC key of SEC 1 is the private key within PKCS #8
+        let pkcs8 = cert.serialize_private_key_der();
+        let (_, info) = x509_parser::der_parser::parse_der(&pkcs8).unwrap();
+        let sec1 = info.as_sequence().unwrap()[2].as_slice().unwrap();
+
+        for (name, contents) in [
+            ("pkcs8.pem", cert.serialize_private_key_pem()),
+            ("ec.pem", pem("EC PRIVATE KEY", sec1)),
+            // PKCS #1, which can't be generated by rcgen
+            ("rsa.pem", include_str!("tls/test-rsa-key.pem").to_string()),
+        ] {
+            let key = read_key(&dir.write(name, &contents)).unwrap();
+            assert!(rustls::sign::any_supported_type(&key).is_ok(), "{}", name);
+        }
+
+        let cert_only = dir.write("cert.pem", &cert.serialize_pem().unwrap());
+        assert!(read_key(&cert_only).is_err());
+        assert!(read_key(&dir.0.join("missing.pem")).is_err());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn reloads_on_change() {
+        let dir = TempDir::new("reload");
+        let tls = dir.tls(&cert(Some("first")));
+        let config = RustlsConfig::from_config(tls.server_config().unwrap());
+        let key = tls.key.clone();
+        tls.watch(config.clone());
+
+        tokio::time::sleep(RELOAD_INTERVAL / 2).await;
+        let first = config.get_inner();
+
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        assert!(Arc::ptr_eq(&first, &config.get_inner()));
+
+        // Files in the middle of an update are skipped
+        dir.write("key.pem", "");
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        assert!(Arc::ptr_eq(&first, &config.get_inner()));
+
+        dir.tls(&cert(Some("second")));
+        assert!(read_key(&key).is_ok());
+        tokio::time::sleep(RELOAD_INTERVAL).await;
+        assert!(!Arc::ptr_eq(&first, &config.get_inner()));
+    }
+}
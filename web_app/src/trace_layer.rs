@@ -8,10 +8,17 @@ use axum::{
 };
 use tracing::{Span, trace};
 
+use crate::request_id;
+
 pub fn trace_layer_make_span_with(request: &Request<Body>) -> Span {
     tracing::error_span!("request",
         uri = %request.uri(),
         method = %request.method(),
+        request_id = request_id::id(request)
+            .map(tracing::field::display)
+            .unwrap_or_else(||
+                tracing::field::display(String::from("<none>"))
+            ),
         source = request.extensions()
             .get::<ConnectInfo<SocketAddr>>()
             .map(|connect_info|
@@ -0,0 +1,28 @@
+//! Single-page UI to try transformations and switch commands, served at `/ui`
+//!
+//! The assets are compiled into the binary. The page calls the API with the credentials
+//! entered into it, so it shows no more than the caller may see.
+
+use axum::{
+    http::header::CONTENT_TYPE,
+    response::{Html, IntoResponse, Redirect},
+    routing::get,
+    Router,
+};
+
+const INDEX: &str = include_str!("ui/index.html");
+const APP: &str = include_str!("ui/app.js");
+
+/// Routes serving the page and its script
+pub fn router<S>() -> Router<S>
+where
+    S: Clone + Send + Sync + 'static,
+{
+    Router::new()
+        .route("/", get(|| async { Redirect::temporary("/ui") }))
+        .route("/ui", get(|| async { Html(INDEX) }))
+        .route(
+            "/ui/app.js",
+            get(|| async { ([(CONTENT_TYPE, "text/javascript")], APP).into_response() }),
+        )
+}
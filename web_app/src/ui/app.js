@@ -0,0 +1,224 @@
+"use strict";
+
+const $ = (id) => document.getElementById(id);
+
+const state = {
+  commands: new Map(),
+  // ETag of the active command, a change is made on top of it
+  etag: null,
+  lastEventId: null,
+  events: null,
+};
+
+function credentials() {
+  return {
+    kind: sessionStorage.getItem("credential-kind") || "api-key",
+    value: sessionStorage.getItem("credential") || "",
+  };
+}
+
+function authHeaders() {
+  const { kind, value } = credentials();
+  if (!value) return {};
+  return kind === "bearer" ? { Authorization: `Bearer ${value}` } : { "X-Api-Key": value };
+}
+
+/// Fetch with the credentials, failing with the `detail` of a problem
+async function api(path, options = {}) {
+  const response = await fetch(path, {
+    ...options,
+    headers: { ...authHeaders(), ...(options.headers || {}) },
+  });
+
+  if (!response.ok) {
+    let detail = `${response.status} ${response.statusText}`;
+    try {
+      const problem = await response.json();
+      detail = problem.detail || detail;
+    } catch (_) {}
+    const error = new Error(detail);
+    error.status = response.status;
+    throw error;
+  }
+
+  return response;
+}
+
+function describe(code) {
+  const command = state.commands.get(code);
+  return command ? `${code} — ${command.description}` : code;
+}
+
+function fillCommands(select, commands) {
+  for (const command of commands) {
+    const option = document.createElement("option");
+    option.value = command.code;
+    option.textContent = `${command.code} — ${command.description}`;
+    select.append(option);
+  }
+}
+
+async function loadCommands() {
+  const commands = await (await api("/v1/commands")).json();
+  state.commands = new Map(commands.map((command) => [command.code, command]));
+
+  for (const select of [$("transform-command"), $("admin-command")]) {
+    select.querySelectorAll("option[value]:not([value=''])").forEach((option) => option.remove());
+    fillCommands(select, commands);
+  }
+}
+
+async function loadWhoami() {
+  const whoami = await (await api("/v1/whoami")).json();
+
+  $("whoami").textContent = whoami.identity
+    ? `Signed in as ${whoami.identity} (${whoami.scopes.join(", ") || "no scopes"})`
+    : "Authentication is off";
+  $("admin").hidden = !whoami.scopes.includes("admin");
+}
+
+function showActive(active, etag) {
+  state.etag = etag;
+
+  const since = active.at ? ` since ${new Date(active.at).toLocaleString()}` : " (default)";
+  $("active").textContent = `${describe(active.command)}${since}`;
+  $("admin-command").value = active.command;
+}
+
+async function loadActive() {
+  const response = await api("/v1/command");
+  showActive(await response.json(), response.headers.get("ETag"));
+}
+
+async function transform() {
+  $("output").textContent = "";
+  $("output").classList.remove("error");
+  $("output-meta").textContent = "Transforming…";
+
+  const body = { input: $("input").value };
+  if ($("transform-command").value) body.command = $("transform-command").value;
+
+  try {
+    const response = await api("/v1/transform", {
+      method: "POST",
+      headers: { "Content-Type": "application/json" },
+      body: JSON.stringify(body),
+    });
+    const result = await response.json();
+
+    $("output").textContent = result.output;
+    const cache = response.headers.get("X-Cache");
+    const processedBy = response.headers.get("X-Processed-By");
+    $("output-meta").textContent = [
+      `command ${result.command}`,
+      `worker #${result.worker_id}`,
+      `${result.elapsed_us}μs`,
+      cache && `cache ${cache}`,
+      processedBy && `processed by ${processedBy}`,
+      `request ${response.headers.get("X-Request-Id")}`,
+    ]
+      .filter(Boolean)
+      .join(" · ");
+  } catch (error) {
+    $("output").textContent = error.message;
+    $("output").classList.add("error");
+    $("output-meta").textContent = "";
+  }
+}
+
+async function apply() {
+  const code = $("admin-command").value;
+  const headers = { "Idempotency-Key": crypto.randomUUID() };
+  // Don't override a change made by someone else meanwhile
+  if (state.etag) headers["If-Match"] = state.etag;
+
+  $("admin-status").textContent = "Applying…";
+  try {
+    await api(`/fnf/${encodeURIComponent(code)}`, { method: "POST", headers });
+    $("admin-status").textContent = `Applied ${code}`;
+  } catch (error) {
+    $("admin-status").textContent =
+      error.status === 412 ? "The command was changed meanwhile, check it and retry" : error.message;
+  }
+  await loadActive().catch(() => {});
+}
+
+function logEvent(event) {
+  const item = document.createElement("li");
+  item.textContent = `${new Date(event.at).toLocaleString()}  #${event.sequence}  ${describe(event.command)}`;
+  $("log").prepend(item);
+}
+
+/// Read server-sent events by fetch, as `EventSource` can't send credentials in headers
+async function followEvents() {
+  if (state.events) state.events.abort();
+  const controller = new AbortController();
+  state.events = controller;
+
+  while (!controller.signal.aborted) {
+    try {
+      const headers = state.lastEventId ? { "Last-Event-ID": state.lastEventId } : {};
+      const response = await api("/v1/commands/events", { headers, signal: controller.signal });
+      $("log-status").textContent = "Live";
+
+      const reader = response.body.pipeThrough(new TextDecoderStream()).getReader();
+      let buffer = "";
+      for (;;) {
+        const { value, done } = await reader.read();
+        if (done) break;
+
+        buffer += value;
+        const frames = buffer.split("\n\n");
+        buffer = frames.pop();
+        frames.forEach(handleFrame);
+      }
+    } catch (error) {
+      if (controller.signal.aborted) return;
+      $("log-status").textContent = `Disconnected: ${error.message}`;
+    }
+
+    $("log-status").textContent = "Reconnecting…";
+    await new Promise((resolve) => setTimeout(resolve, 3000));
+  }
+}
+
+function handleFrame(frame) {
+  let kind = "message";
+  let data = "";
+  for (const line of frame.split("\n")) {
+    if (line.startsWith("id:")) state.lastEventId = line.slice(3).trim();
+    else if (line.startsWith("event:")) kind = line.slice(6).trim();
+    else if (line.startsWith("data:")) data += line.slice(5).trim();
+  }
+  if (kind !== "command" || !data) return;
+
+  const event = JSON.parse(data);
+  logEvent(event);
+  showActive({ command: event.command, at: event.at }, `"${event.sequence}"`);
+}
+
+async function refresh() {
+  $("whoami").classList.remove("error");
+  try {
+    await loadWhoami();
+    await loadCommands();
+    await loadActive();
+    followEvents();
+  } catch (error) {
+    $("whoami").textContent = error.message;
+    $("whoami").classList.add("error");
+    $("admin").hidden = true;
+  }
+}
+
+$("credentials").addEventListener("submit", (event) => {
+  event.preventDefault();
+  sessionStorage.setItem("credential-kind", $("credential-kind").value);
+  sessionStorage.setItem("credential", $("credential").value);
+  refresh();
+});
+$("transform").addEventListener("click", transform);
+$("apply").addEventListener("click", apply);
+
+$("credential-kind").value = credentials().kind;
+refresh();
@@ -0,0 +1,366 @@
+use std::{sync::Arc, time::Instant};
+
+use crate::error::{ErrorDetail, Result};
+use crate::extract::{json_rejected, Timeout, TransformRequest};
+use crate::rate_limit::Charge;
+use crate::request_id;
+use crate::route::{self, TransformResponse};
+use crate::state::{CommandEvent, Config, Nats};
+use axum::{
+    extract::{
+        ws::{close_code, CloseFrame, Message},
+        State, WebSocketUpgrade,
+    },
+    response::Response,
+    Extension,
+};
+use futures::{future::BoxFuture, FutureExt, Sink, SinkExt, Stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use shutdown::Shutdown;
+use tokio::{
+    sync::{
+        broadcast::{self, error::RecvError},
+        mpsc, Semaphore,
+    },
+    time::MissedTickBehavior,
+};
+use tracing::{debug, info, trace, warn, Instrument, Span};
+
+/// A text frame sent by a client
+#[derive(Deserialize)]
+struct Incoming {
+    /// Correlation ID echoed back with the result
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    request: TransformRequest,
+}
+
+/// A text frame sent to a client
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+enum Outgoing {
+    Result {
+        id: Value,
+        #[serde(flatten)]
+        response: TransformResponse,
+    },
+    Error {
+        id: Value,
+        #[serde(flatten)]
+        error: ErrorDetail,
+    },
+    /// The fleet-wide command has changed
+    Command(CommandEvent),
+}
+
+/// Transform texts as they come over a WebSocket
+///
+/// Every text frame is a `TransformRequest` with an optional correlation `id` echoed back.
+/// Frames sent back have `type` of `result` (a `TransformResponse`), `error` (with the
+/// `code` and `detail` of a problem) or `command` (a `CommandEvent`, pushed on every
+/// change of the command). Every request takes a token of the rate limit, a request out of
+/// tokens gets an error of code `rate_limited`.
+#[utoipa::path(
+    get,
+    path = "/v1/ws",
+    tag = "transform",
+    params(Timeout),
+    responses((status = 101, description = "Switched to the WebSocket protocol")),
+)]
+pub async fn handler(
+    ws: WebSocketUpgrade,
+    State(nats): State<Arc<Nats>>,
+    State(config): State<Arc<Config>>,
+    State(shutdown): State<Shutdown>,
+    Timeout(timeout): Timeout,
+    charge: Option<Extension<Charge>>,
+) -> Response {
+    // Keep the span and the ID of the upgrade request for the whole session
+    let request_id = request_id::current();
+    let span = Span::current();
+
+    // Every request of the session is given the timeout taken from the upgrade request
+    let commands = nats.command_events();
+    let transform: Transform = Arc::new(move |request| {
+        let nats = Arc::clone(&nats);
+        let charge = charge.clone();
+
+        async move {
+            if let Some(Extension(charge)) = charge {
+                charge.take(1)?;
+            }
+
+            route::transform_one(&nats, request, timeout).await
+        }
+        .boxed()
+    });
+
+    ws.on_upgrade(move |socket| {
+        let (sink, stream) = socket.split();
+
+        request_id::within(
+            request_id,
+            async move {
+                if let Err(e) = session(sink, stream, transform, commands, config, shutdown).await {
+                    warn!("WebSocket session failed: {:?}", e);
+                }
+            }
+            .instrument(span),
+        )
+    })
+}
+
+/// Transformation of a request of a session
+type Transform =
+    Arc<dyn Fn(TransformRequest) -> BoxFuture<'static, Result<TransformResponse>> + Send + Sync>;
+
+async fn session<W, R>(
+    mut sink: W,
+    mut stream: R,
+    transform: Transform,
+    mut commands: broadcast::Receiver<CommandEvent>,
+    config: Arc<Config>,
+    shutdown: Shutdown,
+) -> Result<()>
+where
+    W: Sink<Message, Error = axum::Error> + Unpin,
+    R: Stream<Item = std::result::Result<Message, axum::Error>> + Unpin,
+{
+    info!("WebSocket session started");
+
+    let (results_tx, mut results_rx) = mpsc::channel(config.ws_max_in_flight);
+    let in_flight = Arc::new(Semaphore::new(config.ws_max_in_flight));
+
+    let mut ping = tokio::time::interval(config.ws_ping_interval);
+    ping.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut last_pong = Instant::now();
+
+    loop {
+        let outgoing = tokio::select! {
+            // Stop reading frames while the limit of requests in flight is reached
+            frame = stream.next(), if in_flight.available_permits() > 0 => match frame {
+                Some(Ok(Message::Text(text))) => {
+                    let Incoming { id, request } = match serde_json::from_str(&text) {
+                        Ok(incoming) => incoming,
+                        Err(e) => {
+                            debug!("Malformed frame: {}", e);
+                            let error = Outgoing::Error {
+                                id: Value::Null,
+                                error: json_rejected(e).into(),
+                            };
+                            send(&mut sink, error).await?;
+                            continue;
+                        }
+                    };
+
+                    let permit = Arc::clone(&in_flight).acquire_owned().await?;
+                    let transformed = transform(request);
+                    let results_tx = results_tx.clone();
+
+                    tokio::spawn(request_id::within(request_id::current(), async move {
+                        let outgoing = match transformed.await {
+                            Ok(response) => Outgoing::Result { id, response },
+                            Err(e) => Outgoing::Error { id, error: e.into() },
+                        };
+
+                        // Release the slot before the result is picked up
+                        drop(permit);
+
+                        // The session may be already closed
+                        let _ = results_tx.send(outgoing).await;
+                    }.in_current_span()));
+
+                    continue;
+                }
+                Some(Ok(Message::Pong(_))) => {
+                    trace!("Got pong");
+                    last_pong = Instant::now();
+                    continue;
+                }
+                Some(Ok(Message::Close(_))) | None => break,
+                Some(Ok(_)) => {
+                    trace!("Ignore frame");
+                    continue;
+                }
+                Some(Err(e)) => return Err(e.into()),
+            },
+            Some(outgoing) = results_rx.recv() => outgoing,
+            event = commands.recv() => match event {
+                Ok(event) => Outgoing::Command(event),
+                Err(RecvError::Lagged(skipped)) => {
+                    warn!("Skipped {} command events", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => break,
+            },
+            _ = ping.tick() => {
+                if last_pong.elapsed() > 2 * config.ws_ping_interval {
+                    warn!("Client stopped responding to pings");
+                    break;
+                }
+
+                trace!("Send ping");
+                sink.send(Message::Ping(Vec::new())).await?;
+                continue;
+            }
+            _ = shutdown.requested() => {
+                info!("Close WebSocket on shutdown");
+                sink.send(Message::Close(Some(CloseFrame {
+                    code: close_code::AWAY,
+                    reason: "Shutting down".into(),
+                })))
+                .await?;
+                break;
+            }
+        };
+
+        send(&mut sink, outgoing).await?;
+    }
+
+    info!("WebSocket session closed");
+
+    Ok(())
+}
+
+async fn send<S>(sink: &mut S, outgoing: Outgoing) -> Result<()>
+where
+    S: Sink<Message, Error = axum::Error> + Unpin,
+{
+    sink.send(Message::Text(serde_json::to_string(&outgoing)?))
+        .await?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Duration,
+    };
+
+    use super::*;
+    use crate::error::ApiError;
+    use futures::channel::mpsc::{unbounded, UnboundedReceiver, UnboundedSender};
+
+    type Frames = UnboundedSender<std::result::Result<Message, axum::Error>>;
+
+    fn config(ws_max_in_flight: usize) -> Arc<Config> {
+        Arc::new(Config {
+            batch_concurrency: 1,
+            ws_max_in_flight,
+            ws_ping_interval: Duration::from_secs(3600),
+            request_timeout: Duration::from_secs(5),
+            max_request_timeout: Duration::from_secs(60),
+        })
+    }
+
+    /// A session over channels standing in for the socket, every request of which fails
+    /// after 100ms
+    fn start(
+        config: Arc<Config>,
+        shutdown: Shutdown,
+        in_flight: Arc<AtomicUsize>,
+        most_in_flight: Arc<AtomicUsize>,
+    ) -> (
+        Frames,
+        UnboundedReceiver<Message>,
+        broadcast::Sender<CommandEvent>,
+        tokio::task::JoinHandle<Result<()>>,
+    ) {
+        let (incoming_tx, incoming_rx) = unbounded();
+        let (outgoing_tx, outgoing_rx) = unbounded();
+        let (commands_tx, commands_rx) = broadcast::channel(1);
+
+        let transform: Transform = Arc::new(move |request| {
+            let in_flight = Arc::clone(&in_flight);
+            let most_in_flight = Arc::clone(&most_in_flight);
+
+            async move {
+                let now = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+                most_in_flight.fetch_max(now, Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                in_flight.fetch_sub(1, Ordering::SeqCst);
+
+                Err(ApiError::InvalidCommand(request.input).into())
+            }
+            .boxed()
+        });
+
+        let session = tokio::spawn(session(
+            outgoing_tx.sink_map_err(axum::Error::new),
+            incoming_rx,
+            transform,
+            commands_rx,
+            config,
+            shutdown,
+        ));
+
+        (incoming_tx, outgoing_rx, commands_tx, session)
+    }
+
+    /// Text frames sent to the client, skipping pings
+    async fn next_text(outgoing: &mut UnboundedReceiver<Message>) -> Option<Value> {
+        while let Some(message) = outgoing.next().await {
+            if let Message::Text(text) = message {
+                return Some(serde_json::from_str(&text).unwrap());
+            }
+        }
+        None
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn requests_in_flight_are_bounded() {
+        let most_in_flight = Arc::new(AtomicUsize::new(0));
+        let (_requested, shutdown) = Shutdown::manual();
+        let (incoming, mut outgoing, _commands, session) = start(
+            config(2),
+            shutdown,
+            Arc::default(),
+            Arc::clone(&most_in_flight),
+        );
+
+        for id in 0..5 {
+            let frame = serde_json::json!({ "id": id, "input": "abc", "command": "uc" });
+            incoming
+                .unbounded_send(Ok(Message::Text(frame.to_string())))
+                .unwrap();
+        }
+
+        let mut ids = Vec::new();
+        for _ in 0..5 {
+            let frame = next_text(&mut outgoing).await.unwrap();
+            assert_eq!(frame["type"], "error");
+            assert_eq!(frame["code"], "invalid_command");
+            ids.push(frame["id"].as_u64().unwrap());
+        }
+        ids.sort();
+
+        assert_eq!(ids, [0, 1, 2, 3, 4]);
+        assert_eq!(most_in_flight.load(Ordering::SeqCst), 2);
+
+        drop(incoming);
+        session.await.unwrap().unwrap();
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn closed_on_shutdown() {
+        let (requested, shutdown) = Shutdown::manual();
+        let (_incoming, mut outgoing, _commands, session) =
+            start(config(1), shutdown, Arc::default(), Arc::default());
+
+        requested.send_replace(true);
+        session.await.unwrap().unwrap();
+
+        let mut close = None;
+        while let Some(message) = outgoing.next().await {
+            if let Message::Close(frame) = message {
+                close = frame;
+            }
+        }
+        let close = close.unwrap();
+        assert_eq!(close.code, close_code::AWAY);
+    }
+}